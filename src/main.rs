@@ -1,16 +1,128 @@
+use async_graphql::http::{playground_source, GraphQLPlaygroundConfig};
+use async_graphql::{Context, EmptyMutation, EmptySubscription, InputObject, Object, Schema, SimpleObject};
 use axum::{
-    extract::{Path, Query},
-    http::StatusCode,
-    response::Json,
-    routing::get,
+    extract::{FromRef, FromRequestParts, Path, Query, Request},
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Json, Response,
+    },
+    routing::{delete, get, head, patch, post, put, MethodRouter},
     Router,
 };
+use rand::distributions::{Alphanumeric, Distribution, WeightedIndex};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use sqlx::{Row, SqlitePool};
-use std::collections::HashMap;
-use tower_http::cors::CorsLayer;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::{QueryBuilder, Row, Sqlite, SqlitePool};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::{Stream, StreamExt};
+use tower_http::{
+    compression::{
+        predicate::{NotForContentType, Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    cors::{Any, CorsLayer},
+    timeout::TimeoutLayer,
+};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
 
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+const DEFAULT_DATABASE_URL: &str = "sqlite:wine_ratings.db";
+const DEFAULT_PORT: u16 = 3000;
+const DEFAULT_MAX_CONNECTIONS: u32 = 10;
+const DEFAULT_MIN_CONNECTIONS: u32 = 0;
+// Prepared statements kept per connection; matches sqlx's own default and comfortably
+// covers the distinct queries this service issues.
+const DEFAULT_STATEMENT_CACHE_CAPACITY: usize = 100;
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 30_000;
+const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5_000;
+const DEFAULT_WRITE_RETRIES: u32 = 3;
+const DEFAULT_WRITE_RETRY_BACKOFF_MS: u64 = 20;
+const DRAIN_LOG_INTERVAL: Duration = Duration::from_secs(1);
+// Seconds clients are told to wait before retrying a 503 caused by a locked database.
+const RETRY_AFTER_SECONDS: u32 = 1;
+const DEFAULT_COMPRESSION_MIN_BYTES: u16 = 1024;
+const DEFAULT_RATING_DECIMALS: u32 = 2;
+const DEFAULT_ANONYMIZED_FIELDS: &[&str] = &["notes", "reviewer"];
+const DEFAULT_QUERY_ALIASES: &[(&str, &str)] = &[("grape", "variety"), ("appellation", "region")];
+const DEFAULT_PEAK_AGES: &[(&str, f64)] = &[("Red Wine", 8.0), ("White Wine", 3.0)];
+const FALLBACK_PEAK_AGE: f64 = 5.0;
+const SECONDS_PER_YEAR: u64 = 31_556_952;
+const MAX_RATING_DECIMALS: u32 = 10;
+const WINE_EVENTS_CAPACITY: usize = 64;
+// Routes that take a raw CSV upload instead of a JSON body.
+const CSV_BODY_ROUTES: &[&str] = &["/wines/import/validate"];
+const MAX_IMPORT_BYTES: usize = 5 * 1024 * 1024;
+const IMPORT_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const DEFAULT_MAX_SEARCH_RESULTS: usize = 500;
+const TOTAL_COUNT_HEADER: &str = "x-total-count";
+const DATASET_VERSION_HEADER: &str = "x-dataset-version";
+const MAINTENANCE_NOTICE_HEADER: &str = "x-maintenance-notice";
+const SEARCH_SUGGESTION_HEADER: &str = "x-search-suggestion";
+const SEARCH_TRUNCATED_HEADER: &str = "x-search-truncated";
+// The composite index also serves region-only lookups, so region has no index of its own.
+const FILTER_INDEXES: &[(&str, &str)] = &[
+    ("idx_wine_ratings_tenant_region_rating", "tenant_id, region, rating"),
+    ("idx_wine_ratings_tenant_variety", "tenant_id, variety"),
+    ("idx_wine_ratings_tenant_rating", "tenant_id, rating"),
+];
+// Superseded by the tenant-prefixed indexes above.
+const LEGACY_INDEXES: &[&str] = &[
+    "idx_wine_ratings_region_rating",
+    "idx_wine_ratings_variety",
+    "idx_wine_ratings_rating",
+    "idx_wine_ratings_external_id",
+    "idx_wine_ratings_name_vintage",
+];
+const DEFAULT_TENANT: &str = "default";
+const TENANT_HEADER: &str = "x-tenant-id";
+const USER_HEADER: &str = "x-user-id";
+const PREFER_HEADER: &str = "prefer";
+const PREFERENCE_APPLIED_HEADER: &str = "preference-applied";
+const RECOMMENDATION_MIN_RATING: f64 = 85.0;
+const DEFAULT_RECOMMENDATIONS: i64 = 10;
+// How many catalog-average ratings each region's average is blended with when ranking regions.
+const REGION_RANKING_PRIOR_WEIGHT: f64 = 5.0;
+const MAX_IDENTIFIER_LENGTH: usize = 64;
+// Writes to these tables bump the dataset version reported by GET /version.
+const VERSIONED_TABLES: &[&str] = &["wine_ratings", "reviews", "wine_tags"];
+// Ratings from a source with a configured scale are rescaled to 100 points; others are taken as-is.
+macro_rules! normalized_rating {
+    () => {
+        "(rating * 100.0 / IFNULL((SELECT scale FROM source_scales WHERE source = wine_ratings.source), 100.0))"
+    };
+}
+const WINE_COLUMNS: &str = concat!(
+    "id, name, region, variety, rating, notes, vintage, image_url, latitude, longitude, price, currency, external_id, version, source, ",
+    normalized_rating!(),
+    " AS rating_normalized"
+);
+const NORMALIZED_RATING: &str = normalized_rating!();
+const SAVED_FILTER_ID_LENGTH: usize = 8;
+const NORMALIZE_BATCH_SIZE: i64 = 500;
+// Largest edit distance at which an empty search still offers a "did you mean" name.
+const SUGGESTION_MAX_DISTANCE: usize = 3;
+const SNIPPET_CONTEXT_CHARS: usize = 40;
+const NAME_SEARCH: &str = "COALESCE(name_search, LOWER(name))";
+const NOTES_SEARCH: &str = "COALESCE(notes_search, LOWER(notes))";
+const COMMENT_SEARCH: &str = "COALESCE(r.comment_search, LOWER(r.comment))";
+const DEFAULT_SEARCH_WINDOW: &str = "7d";
+const DEFAULT_POPULAR_SEARCHES: i64 = 10;
+const MIN_RATING: f64 = 0.0;
+const MAX_RATING: f64 = 100.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow, SimpleObject)]
 struct Wine {
     id: i64,
     name: String,
@@ -18,19 +130,680 @@ struct Wine {
     variety: Option<String>,
     rating: Option<f64>,
     notes: Option<String>,
+    vintage: Option<i64>,
+    image_url: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    price: Option<f64>,
+    currency: Option<String>,
+    external_id: Option<String>,
+    version: i64,
+    source: Option<String>,
+    rating_normalized: Option<f64>,
+    // Only filled in when the request asks for a display_currency.
+    #[sqlx(skip)]
+    #[graphql(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    price_converted: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+struct Review {
+    id: i64,
+    wine_id: i64,
+    reviewer: Option<String>,
+    score: Option<f64>,
+    comment: Option<String>,
+    created_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewReview {
+    reviewer: Option<String>,
+    score: Option<f64>,
+    comment: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+struct RatingPoint {
+    created_at: String,
+    score: f64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct ReviewMatchRow {
+    #[sqlx(flatten)]
+    wine: Wine,
+    matching_reviews: i64,
+    first_comment: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ReviewMatch {
+    #[serde(flatten)]
+    wine: Wine,
+    snippet: String,
+    matching_reviews: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WineWithReviews {
+    #[serde(flatten)]
+    wine: Wine,
+    reviews: Vec<Review>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewWine {
+    name: String,
+    region: Option<String>,
+    variety: Option<String>,
+    rating: Option<f64>,
+    notes: Option<String>,
+    vintage: Option<i64>,
+    image_url: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    price: Option<f64>,
+    currency: Option<String>,
+    source: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct WinePatch {
+    name: Option<String>,
+    region: Option<String>,
+    variety: Option<String>,
+    rating: Option<f64>,
+    notes: Option<String>,
+    vintage: Option<i64>,
+    image_url: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    price: Option<f64>,
+    currency: Option<String>,
+    source: Option<String>,
+}
+
+impl WinePatch {
+    fn apply(self, wine: &Wine) -> NewWine {
+        NewWine {
+            name: self.name.unwrap_or_else(|| wine.name.clone()),
+            region: self.region.or_else(|| wine.region.clone()),
+            variety: self.variety.or_else(|| wine.variety.clone()),
+            rating: self.rating.or(wine.rating),
+            notes: self.notes.or_else(|| wine.notes.clone()),
+            vintage: self.vintage.or(wine.vintage),
+            image_url: self.image_url.or_else(|| wine.image_url.clone()),
+            latitude: self.latitude.or(wine.latitude),
+            longitude: self.longitude.or(wine.longitude),
+            price: self.price.or(wine.price),
+            currency: self.currency.or_else(|| wine.currency.clone()),
+            source: self.source.or_else(|| wine.source.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct GeoJsonPoint {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    // GeoJSON orders positions as [longitude, latitude].
+    coordinates: [f64; 2],
+}
+
+#[derive(Debug, Serialize)]
+struct GeoJsonProperties {
+    name: String,
+    region: Option<String>,
+    rating: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct GeoJsonFeature {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    id: i64,
+    geometry: GeoJsonPoint,
+    properties: GeoJsonProperties,
+}
+
+#[derive(Debug, Serialize)]
+struct GeoJsonFeatureCollection {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    features: Vec<GeoJsonFeature>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BoundingBox {
+    min_lat: f64,
+    max_lat: f64,
+    min_lng: f64,
+    max_lng: f64,
+}
+
+impl NewReview {
+    fn validation_errors(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self.score.is_some_and(|score| !is_valid_rating(score)) {
+            errors.push(FieldError::new(
+                "score",
+                &format!("must be between {} and {}", MIN_RATING, MAX_RATING),
+            ));
+        }
+        errors
+    }
+}
+
+impl NewWine {
+    fn validation_errors(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self.name.trim().is_empty() {
+            errors.push(FieldError::new("name", "must not be empty"));
+        }
+        if self.rating.is_some_and(|rating| !is_valid_rating(rating)) {
+            errors.push(FieldError::new(
+                "rating",
+                &format!("must be between {} and {}", MIN_RATING, MAX_RATING),
+            ));
+        }
+        if self.image_url.as_deref().is_some_and(|url| !is_http_url(url)) {
+            errors.push(FieldError::new("image_url", "must be an absolute http or https URL"));
+        }
+        if !is_valid_coordinates(self.latitude, None) {
+            errors.push(FieldError::new("latitude", "must be between -90 and 90"));
+        }
+        if !is_valid_coordinates(None, self.longitude) {
+            errors.push(FieldError::new("longitude", "must be between -180 and 180"));
+        }
+        if self.price.is_some_and(|price| !price.is_finite() || price < 0.0) {
+            errors.push(FieldError::new("price", "must not be negative"));
+        }
+        if self.currency.as_deref().is_some_and(|currency| !is_currency_code(currency)) {
+            errors.push(FieldError::new("currency", "must be a three-letter ISO 4217 code such as EUR"));
+        }
+        errors
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FieldError {
+    field: String,
+    message: String,
+}
+
+impl FieldError {
+    fn new(field: &str, message: &str) -> Self {
+        Self {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ValidationErrors {
+    errors: Vec<FieldError>,
+}
+
+fn validation_failed(errors: Vec<FieldError>) -> Response {
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(ValidationErrors { errors })).into_response()
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum FileFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    format: Option<FileFormat>,
+    anonymize: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UrlImport {
+    url: String,
+    format: FileFormat,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ImportSummary {
+    imported: usize,
+    duplicates: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ImportRowReport {
+    row: usize,
+    valid: bool,
+    errors: Vec<FieldError>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ImportValidationSummary {
+    total: usize,
+    valid: usize,
+    invalid: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ImportValidation {
+    rows: Vec<ImportRowReport>,
+    summary: ImportValidationSummary,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UpsertResult {
+    created: bool,
+    wine: Wine,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PatchResult {
+    wine: Wine,
+    changed: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VersionConflict {
+    error: String,
+    current_version: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ConflictError {
+    error: String,
+    name: String,
+    vintage: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IncompleteQuery {
+    field: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IncompleteWine {
+    #[serde(flatten)]
+    wine: Wine,
+    missing: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OutlierQuery {
+    sigma: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RatingOutlier {
+    #[serde(flatten)]
+    wine: Wine,
+    z_score: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VarietyOutliers {
+    variety: String,
+    count: usize,
+    mean: f64,
+    std_dev: Option<f64>,
+    outliers: Vec<RatingOutlier>,
 }
 
 #[derive(Debug, Deserialize)]
+struct ConfidenceQuery {
+    variety: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ConfidenceInterval {
+    variety: Option<String>,
+    count: usize,
+    mean: Option<f64>,
+    std_dev: Option<f64>,
+    lower: Option<f64>,
+    upper: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PriceRatingCorrelation {
+    coefficient: Option<f64>,
+    count: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RatingPercentile {
+    percentile: f64,
+    rating: f64,
+    count: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RequestStats {
+    total_requests: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PoolStats {
+    size: u32,
+    idle: usize,
+    in_use: usize,
+    max_connections: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IntegrityReport {
+    orphaned_reviews: i64,
+    orphaned_tags: i64,
+    healthy: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TagAssignment {
+    tag: String,
+    tagged: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedFilter {
+    id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HealthStatus {
+    status: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DatasetVersion {
+    version: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OptimizeReport {
+    freed_bytes: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+struct RegionAlias {
+    alias: String,
+    canonical: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RatingUpdate {
+    id: i64,
+    rating: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RegionBackfillSummary {
+    updated: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RatingUpdateSummary {
+    updated: u64,
+    not_found: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum NullsOrder {
+    First,
+    #[default]
+    Last,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum Grade {
+    A,
+    B,
+    C,
+    D,
+    F,
+}
+
+impl Grade {
+    // Inclusive lower bound and exclusive upper bound; A is open-ended and F has no floor.
+    fn rating_range(self) -> (Option<f64>, Option<f64>) {
+        match self {
+            Grade::A => (Some(90.0), None),
+            Grade::B => (Some(80.0), Some(90.0)),
+            Grade::C => (Some(70.0), Some(80.0)),
+            Grade::D => (Some(60.0), Some(70.0)),
+            Grade::F => (None, Some(60.0)),
+        }
+    }
+}
+
+impl std::str::FromStr for Grade {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "A" => Ok(Grade::A),
+            "B" => Ok(Grade::B),
+            "C" => Ok(Grade::C),
+            "D" => Ok(Grade::D),
+            "F" => Ok(Grade::F),
+            _ => Err("expected one of A, B, C, D, F".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ListFormat {
+    #[default]
+    Json,
+    Ndjson,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct WineFilters {
+    q: Option<String>,
     region: Option<String>,
+    region_exact: Option<String>,
+    exclude_region: Option<String>,
     variety: Option<String>,
+    variety_exact: Option<String>,
     min_rating: Option<f64>,
     max_rating: Option<f64>,
+    has_notes: Option<bool>,
+    min_reviews: Option<u32>,
+    grade: Option<Grade>,
+    above_average: Option<bool>,
+    distinct_names: Option<bool>,
+    notes_length: Option<usize>,
+    display_currency: Option<String>,
+    ids_only: Option<bool>,
+    include_reviews: Option<bool>,
+    sort: Option<String>,
+    nulls: Option<NullsOrder>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    format: Option<ListFormat>,
+    strict: Option<bool>,
+}
+
+impl WineFilters {
+    // Blank substring filters match (nearly) every wine, so they do not count.
+    fn has_conditions(&self) -> bool {
+        let present = |value: &Option<String>| {
+            value
+                .as_deref()
+                .is_some_and(|value| value.split(',').any(|part| !part.trim().is_empty()))
+        };
+        present(&self.q)
+            || present(&self.region)
+            || self.region_exact.is_some()
+            || present(&self.exclude_region)
+            || present(&self.variety)
+            || self.variety_exact.is_some()
+            || self.min_rating.is_some()
+            || self.max_rating.is_some()
+            || self.has_notes.is_some()
+            || self.min_reviews.is_some_and(|min_reviews| min_reviews > 0)
+            || self.grade.is_some()
+            || self.above_average.is_some()
+            || self.distinct_names == Some(true)
+    }
+}
+
+const WINE_FILTER_PARAMS: &[&str] = &[
+    "q",
+    "region",
+    "region_exact",
+    "exclude_region",
+    "variety",
+    "variety_exact",
+    "min_rating",
+    "max_rating",
+    "has_notes",
+    "min_reviews",
+    "grade",
+    "above_average",
+    "distinct_names",
+    "notes_length",
+    "display_currency",
+    "ids_only",
+    "include_reviews",
+    "sort",
+    "nulls",
+    "limit",
+    "offset",
+    "format",
+    "strict",
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Drinkability {
+    id: i64,
+    score: f64,
+    rating: f64,
+    vintage: i64,
+    age: i64,
+    variety: Option<String>,
+    peak_age: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ErrorMessage {
+    error: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UnknownParamsError {
+    error: String,
+    unknown: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DisplayCurrencyQuery {
+    display_currency: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct SearchQuery {
     q: String,
+    highlight: Option<bool>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PopularSearchQuery {
+    window: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PopularSearch {
+    term: String,
+    count: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecommendationQuery {
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+struct Recommendation {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    wine: Wine,
+    affinity: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WineFacets {
+    region: BTreeMap<String, i64>,
+    variety: BTreeMap<String, i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FlavorMatch {
+    #[serde(flatten)]
+    wine: Wine,
+    matched_flavors: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HighlightedWine {
+    #[serde(flatten)]
+    wine: Wine,
+    highlighted_name: String,
+    highlighted_notes: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RegionMedian {
+    median: f64,
+    count: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RegionRanking {
+    region: String,
+    score: f64,
+    avg_rating: f64,
+    count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VintageTrend {
+    vintage: i64,
+    avg_rating: f64,
+    count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VarietyLeader {
+    id: i64,
+    name: String,
+    rating: f64,
+}
+
+// A sub-stat that could not be computed is left out and named in warnings instead.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StatsOverview {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_wines: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    average_rating: Option<Option<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    regions: Option<BTreeMap<String, i64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    varieties: Option<BTreeMap<String, VarietyInfo>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_reviews: Option<i64>,
+    warnings: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -39,267 +812,8846 @@ struct VarietyInfo {
     avg_rating: f64,
 }
 
-async fn get_wines(
-    Query(filters): Query<WineFilters>,
-    axum::extract::State(pool): axum::extract::State<SqlitePool>,
-) -> Result<Json<Vec<Wine>>, StatusCode> {
-    let base_query = "SELECT id, name, region, variety, rating, notes FROM wine_ratings";
-    
-    match (&filters.region, &filters.variety, filters.min_rating, filters.max_rating) {
-        (None, None, None, None) => {
-            let wines = sqlx::query_as::<_, Wine>(base_query)
-                .fetch_all(&pool)
+#[derive(Debug, Deserialize)]
+struct RegionQuery {
+    strict: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DuplicateQuery {
+    by_region: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BulkUpdate {
+    updated: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct NameCollision {
+    name: String,
+    regions: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DuplicateGroup {
+    name: String,
+    region: Option<String>,
+    wines: Vec<Wine>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FieldSchema {
+    name: String,
+    #[serde(rename = "type")]
+    field_type: String,
+    nullable: bool,
+    filterable: bool,
+    sortable: bool,
+}
+
+const WINE_FIELDS: &[(&str, &str, bool)] = &[
+    ("id", "integer", false),
+    ("name", "string", false),
+    ("region", "string", true),
+    ("variety", "string", true),
+    ("rating", "float", true),
+    ("notes", "string", true),
+    ("vintage", "integer", true),
+    ("image_url", "string", true),
+    ("latitude", "float", true),
+    ("longitude", "float", true),
+    ("price", "float", true),
+    ("currency", "string", true),
+    ("external_id", "string", true),
+    ("version", "integer", false),
+    ("source", "string", true),
+    ("rating_normalized", "float", true),
+];
+
+const REVIEW_FIELDS: &[&str] = &["id", "wine_id", "reviewer", "score", "comment", "created_at"];
+
+const FILTERABLE_COLUMNS: &[&str] = &["region", "variety", "rating"];
+
+#[derive(Clone)]
+struct Webhook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Webhook {
+    fn new(url: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+
+    fn notify(&self, wine: &Wine) {
+        let payload = match serde_json::to_value(wine) {
+            Ok(payload) => payload,
+            Err(err) => {
+                tracing::warn!("failed to serialize webhook payload: {}", err);
+                return;
+            }
+        };
+        let client = self.client.clone();
+        let url = self.url.clone();
+
+        tokio::spawn(async move {
+            let result = client
+                .post(&url)
+                .json(&payload)
+                .send()
                 .await
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-            Ok(Json(wines))
-        }
-        _ => {
-            let mut conditions = Vec::new();
-            let mut query = base_query.to_string();
-            
-            if let Some(region) = &filters.region {
-                conditions.push(format!("region LIKE '%{}%'", region.replace("'", "''")));
+                .and_then(|response| response.error_for_status());
+            if let Err(err) = result {
+                tracing::warn!("webhook notification to {} failed: {}", url, err);
+            }
+        });
+    }
+}
+
+#[derive(Clone)]
+struct ReviewLoader {
+    pool: SqlitePool,
+    queries: Arc<AtomicU64>,
+}
+
+impl ReviewLoader {
+    fn new(pool: SqlitePool) -> Self {
+        Self {
+            pool,
+            queries: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    #[cfg(test)]
+    fn query_count(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    async fn load(&self, wine_ids: &[i64]) -> Result<HashMap<i64, Vec<Review>>, sqlx::Error> {
+        let mut reviews: HashMap<i64, Vec<Review>> = HashMap::new();
+        if wine_ids.is_empty() {
+            return Ok(reviews);
+        }
+
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "SELECT id, wine_id, reviewer, score, comment, created_at FROM reviews WHERE wine_id IN (",
+        );
+        let mut ids = query.separated(", ");
+        for id in wine_ids {
+            ids.push_bind(*id);
+        }
+        query.push(") ORDER BY wine_id, created_at, id");
+
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        let rows = query.build_query_as::<Review>().fetch_all(&self.pool).await?;
+        for review in rows {
+            reviews.entry(review.wine_id).or_default().push(review);
+        }
+
+        Ok(reviews)
+    }
+
+    async fn attach(&self, wines: Vec<Wine>) -> Result<Vec<WineWithReviews>, sqlx::Error> {
+        let ids: Vec<i64> = wines.iter().map(|wine| wine.id).collect();
+        let mut reviews = self.load(&ids).await?;
+
+        Ok(wines
+            .into_iter()
+            .map(|wine| WineWithReviews {
+                reviews: reviews.remove(&wine.id).unwrap_or_default(),
+                wine,
+            })
+            .collect())
+    }
+}
+
+// Runs one piece of work per key at a time; callers that arrive while it is running wait
+// for and share its result. Nothing is kept once the work finishes, so this is not a cache.
+#[derive(Clone)]
+struct SingleFlight<T> {
+    in_flight: Arc<std::sync::Mutex<HashMap<String, Arc<tokio::sync::OnceCell<T>>>>>,
+}
+
+impl<T> Default for SingleFlight<T> {
+    fn default() -> Self {
+        Self {
+            in_flight: Arc::default(),
+        }
+    }
+}
+
+impl<T: Clone> SingleFlight<T> {
+    async fn run(&self, key: String, work: impl std::future::Future<Output = T>) -> T {
+        let shared = self.in_flight.lock().unwrap().entry(key.clone()).or_default().clone();
+        let result = shared.get_or_init(|| work).await.clone();
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.get(&key).is_some_and(|current| Arc::ptr_eq(current, &shared)) {
+            in_flight.remove(&key);
+        }
+        result
+    }
+}
+
+type WinePage = (i64, Vec<Wine>);
+
+// Concurrent /wines requests with the same tenant, filters and page share one count + fetch.
+#[derive(Clone)]
+struct WineQueries {
+    pool: SqlitePool,
+    enabled: bool,
+    flights: SingleFlight<Result<WinePage, StatusCode>>,
+    queries: Arc<AtomicU64>,
+}
+
+impl WineQueries {
+    fn new(pool: SqlitePool, enabled: bool) -> Self {
+        Self {
+            pool,
+            enabled,
+            flights: SingleFlight::default(),
+            queries: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    #[cfg(test)]
+    fn query_count(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    async fn fetch(&self, tenant: &Tenant, filters: &WineFilters, page: (Option<i64>, i64)) -> Result<WinePage, StatusCode> {
+        if !self.enabled {
+            return self.query(tenant, filters, page).await;
+        }
+
+        let key = format!(
+            "{}|{:?}|{}",
+            tenant.0,
+            page,
+            serde_json::to_string(filters).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        );
+        self.flights.run(key, self.query(tenant, filters, page)).await
+    }
+
+    async fn query(&self, tenant: &Tenant, filters: &WineFilters, page: (Option<i64>, i64)) -> Result<WinePage, StatusCode> {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        let total = count_query(tenant, filters)
+            .build_query_scalar::<i64>()
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let wines = fetch_filtered_wines(&self.pool, tenant, filters, page).await?;
+        Ok((total, wines))
+    }
+}
+
+#[derive(Clone, FromRef)]
+struct AppState {
+    pool: SqlitePool,
+    config: Arc<Config>,
+    reviews: ReviewLoader,
+    wine_queries: WineQueries,
+    events: broadcast::Sender<(Tenant, Wine)>,
+    webhook: Option<Webhook>,
+    schema: WineSchema,
+    requests: RequestCounter,
+    in_flight: InFlightGauge,
+    maintenance: MaintenanceLock,
+    routes: RouteIndex,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Tenant(String);
+
+impl Default for Tenant {
+    fn default() -> Self {
+        Self(DEFAULT_TENANT.to_string())
+    }
+}
+
+fn is_valid_identifier(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_IDENTIFIER_LENGTH && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn json_error(status: StatusCode, error: &str) -> Response {
+    (status, Json(ErrorMessage { error: error.to_string() })).into_response()
+}
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for Tenant
+where
+    Arc<Config>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let config = Arc::<Config>::from_ref(state);
+        // With TENANT_TOKENS configured the tenant comes only from the bearer token, so a
+        // client cannot name another tenant; otherwise a trusted gateway sets the header.
+        if !config.tenant_tokens.is_empty() {
+            let token = parts
+                .headers
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "));
+            return config
+                .tenant_tokens
+                .iter()
+                .find(|(known, _)| Some(known.as_str()) == token)
+                .map(|(_, tenant)| Tenant(tenant.clone()))
+                .ok_or_else(|| json_error(StatusCode::UNAUTHORIZED, "a valid tenant bearer token is required"));
+        }
+
+        match parts.headers.get(TENANT_HEADER) {
+            None => Ok(Tenant::default()),
+            Some(value) => match value.to_str() {
+                Ok(tenant) if is_valid_identifier(tenant) => Ok(Tenant(tenant.to_string())),
+                _ => Err(json_error(
+                    StatusCode::BAD_REQUEST,
+                    "X-Tenant-Id must be 1-64 letters, digits, '-' or '_'",
+                )),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct CurrentUser(String);
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for CurrentUser
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    // Sign-in happens at the gateway, which forwards the authenticated user's id.
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        match parts.headers.get(USER_HEADER) {
+            None => Err(json_error(StatusCode::UNAUTHORIZED, "X-User-Id is required")),
+            Some(value) => match value.to_str() {
+                Ok(user) if is_valid_identifier(user) => Ok(CurrentUser(user.to_string())),
+                _ => Err(json_error(
+                    StatusCode::BAD_REQUEST,
+                    "X-User-Id must be 1-64 letters, digits, '-' or '_'",
+                )),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct RequestCounter(Arc<AtomicU64>);
+
+#[derive(Clone, Default)]
+struct InFlightGauge(Arc<AtomicU64>);
+
+impl InFlightGauge {
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn enter(&self) -> InFlightRequest {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        InFlightRequest(self.0.clone())
+    }
+}
+
+// Decrements on drop so requests cancelled mid-flight (timeouts, client disconnects) are released too.
+struct InFlightRequest(Arc<AtomicU64>);
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone, Default)]
+struct RouteIndex(Arc<Vec<RouteInfo>>);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RouteInfo {
+    method: String,
+    path: String,
+    description: String,
+}
+
+#[derive(Clone, Default)]
+struct MaintenanceLock(Arc<tokio::sync::Mutex<()>>);
+
+#[derive(Debug, Clone)]
+struct Config {
+    database_url: String,
+    host: IpAddr,
+    port: u16,
+    max_connections: u32,
+    min_connections: u32,
+    statement_cache_capacity: usize,
+    busy_timeout: Duration,
+    write_retries: u32,
+    write_retry_backoff: Duration,
+    cors_origins: Vec<HeaderValue>,
+    webhook_url: Option<String>,
+    request_timeout: Duration,
+    max_page_size: Option<i64>,
+    page_size_mode: PageSizeMode,
+    max_concurrent_requests: usize,
+    concurrency_overflow: ConcurrencyOverflow,
+    max_search_results: usize,
+    compression_min_bytes: u16,
+    rating_decimals: u32,
+    admin_endpoints_enabled: bool,
+    admin_token: Option<String>,
+    import_allow_private_hosts: bool,
+    unique_wine_names: bool,
+    log_format: LogFormat,
+    default_sort: Option<String>,
+    maintenance_message: Option<HeaderValue>,
+    strict_query_params: bool,
+    strict_region_lookup: bool,
+    coalesce_queries: bool,
+    peak_ages: Vec<(String, f64)>,
+    source_scales: Vec<(String, f64)>,
+    default_currency: String,
+    currency_rates: Vec<(String, f64)>,
+    tenant_tokens: Vec<(String, String)>,
+    anonymized_fields: Vec<String>,
+    query_aliases: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err("expected pretty or json".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum PageSizeMode {
+    #[default]
+    Clamp,
+    Reject,
+}
+
+impl FromStr for PageSizeMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "clamp" => Ok(Self::Clamp),
+            "reject" => Ok(Self::Reject),
+            _ => Err("expected clamp or reject".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum ConcurrencyOverflow {
+    #[default]
+    Queue,
+    Reject,
+}
+
+impl FromStr for ConcurrencyOverflow {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "queue" => Ok(Self::Queue),
+            "reject" => Ok(Self::Reject),
+            _ => Err("expected queue or reject".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum PageError {
+    Invalid,
+    TooLarge { max: i64 },
+}
+
+impl std::fmt::Display for PageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid => write!(f, "limit must be at least 1 and offset must not be negative"),
+            Self::TooLarge { max } => write!(f, "limit must not exceed the maximum page size of {}", max),
+        }
+    }
+}
+
+impl IntoResponse for PageError {
+    fn into_response(self) -> Response {
+        let error = ErrorMessage { error: self.to_string() };
+        (StatusCode::BAD_REQUEST, Json(error)).into_response()
+    }
+}
+
+#[derive(Debug)]
+struct ConfigError {
+    key: &'static str,
+    value: String,
+    reason: String,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid value {:?} for {}: {}", self.value, self.key, self.reason)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            database_url: DEFAULT_DATABASE_URL.to_string(),
+            host: IpAddr::from([0, 0, 0, 0]),
+            port: DEFAULT_PORT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            min_connections: DEFAULT_MIN_CONNECTIONS,
+            statement_cache_capacity: DEFAULT_STATEMENT_CACHE_CAPACITY,
+            busy_timeout: Duration::from_millis(DEFAULT_BUSY_TIMEOUT_MS),
+            write_retries: DEFAULT_WRITE_RETRIES,
+            write_retry_backoff: Duration::from_millis(DEFAULT_WRITE_RETRY_BACKOFF_MS),
+            cors_origins: Vec::new(),
+            webhook_url: None,
+            request_timeout: Duration::from_millis(DEFAULT_REQUEST_TIMEOUT_MS),
+            max_page_size: None,
+            page_size_mode: PageSizeMode::default(),
+            max_concurrent_requests: 0,
+            concurrency_overflow: ConcurrencyOverflow::default(),
+            max_search_results: DEFAULT_MAX_SEARCH_RESULTS,
+            compression_min_bytes: DEFAULT_COMPRESSION_MIN_BYTES,
+            rating_decimals: DEFAULT_RATING_DECIMALS,
+            admin_endpoints_enabled: true,
+            admin_token: None,
+            import_allow_private_hosts: false,
+            unique_wine_names: false,
+            log_format: LogFormat::default(),
+            default_sort: None,
+            maintenance_message: None,
+            strict_query_params: false,
+            strict_region_lookup: false,
+            coalesce_queries: true,
+            peak_ages: DEFAULT_PEAK_AGES
+                .iter()
+                .map(|(variety, age)| (variety.to_string(), *age))
+                .collect(),
+            source_scales: Vec::new(),
+            default_currency: "USD".to_string(),
+            currency_rates: Vec::new(),
+            tenant_tokens: Vec::new(),
+            anonymized_fields: DEFAULT_ANONYMIZED_FIELDS.iter().map(|field| field.to_string()).collect(),
+            query_aliases: DEFAULT_QUERY_ALIASES
+                .iter()
+                .map(|(alias, canonical)| (alias.to_string(), canonical.to_string()))
+                .collect(),
+        }
+    }
+}
+
+fn parse_var<T>(vars: &HashMap<String, String>, key: &'static str, default: T) -> Result<T, ConfigError>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match vars.get(key) {
+        Some(value) => value.trim().parse().map_err(|err: T::Err| ConfigError {
+            key,
+            value: value.clone(),
+            reason: err.to_string(),
+        }),
+        None => Ok(default),
+    }
+}
+
+impl Config {
+    fn from_env() -> Result<Self, ConfigError> {
+        Self::from_vars(&std::env::vars().collect())
+    }
+
+    fn from_vars(vars: &HashMap<String, String>) -> Result<Self, ConfigError> {
+        let defaults = Self::default();
+        let config = Self {
+            database_url: vars.get("DATABASE_URL").cloned().unwrap_or(defaults.database_url),
+            host: parse_var(vars, "HOST", defaults.host)?,
+            port: parse_var(vars, "PORT", defaults.port)?,
+            max_connections: parse_var(vars, "DATABASE_MAX_CONNECTIONS", defaults.max_connections)?,
+            min_connections: parse_var(vars, "DATABASE_MIN_CONNECTIONS", defaults.min_connections)?,
+            statement_cache_capacity: parse_var(vars, "STATEMENT_CACHE_CAPACITY", defaults.statement_cache_capacity)?,
+            busy_timeout: Duration::from_millis(parse_var(vars, "DATABASE_BUSY_TIMEOUT_MS", DEFAULT_BUSY_TIMEOUT_MS)?),
+            write_retries: parse_var(vars, "DATABASE_WRITE_RETRIES", DEFAULT_WRITE_RETRIES)?,
+            write_retry_backoff: Duration::from_millis(parse_var(
+                vars,
+                "DATABASE_WRITE_RETRY_BACKOFF_MS",
+                DEFAULT_WRITE_RETRY_BACKOFF_MS,
+            )?),
+            cors_origins: parse_cors_origins(vars)?,
+            webhook_url: vars.get("WEBHOOK_URL").filter(|url| !url.is_empty()).cloned(),
+            request_timeout: Duration::from_millis(parse_var(vars, "REQUEST_TIMEOUT_MS", DEFAULT_REQUEST_TIMEOUT_MS)?),
+            max_page_size: vars.get("MAX_PAGE_SIZE").map(|_| parse_var(vars, "MAX_PAGE_SIZE", 0)).transpose()?,
+            page_size_mode: parse_var(vars, "PAGE_SIZE_MODE", defaults.page_size_mode)?,
+            max_concurrent_requests: parse_var(vars, "MAX_CONCURRENT_REQUESTS", defaults.max_concurrent_requests)?,
+            concurrency_overflow: parse_var(vars, "CONCURRENCY_OVERFLOW", defaults.concurrency_overflow)?,
+            max_search_results: parse_var(vars, "MAX_SEARCH_RESULTS", defaults.max_search_results)?,
+            compression_min_bytes: parse_var(vars, "COMPRESSION_MIN_BYTES", defaults.compression_min_bytes)?,
+            rating_decimals: parse_var(vars, "RATING_DECIMALS", defaults.rating_decimals)?,
+            admin_endpoints_enabled: parse_var(vars, "ADMIN_ENDPOINTS_ENABLED", defaults.admin_endpoints_enabled)?,
+            admin_token: vars.get("ADMIN_TOKEN").filter(|token| !token.is_empty()).cloned(),
+            import_allow_private_hosts: parse_var(
+                vars,
+                "IMPORT_ALLOW_PRIVATE_HOSTS",
+                defaults.import_allow_private_hosts,
+            )?,
+            unique_wine_names: parse_var(vars, "UNIQUE_WINE_NAMES", defaults.unique_wine_names)?,
+            log_format: parse_var(vars, "LOG_FORMAT", defaults.log_format)?,
+            default_sort: vars.get("DEFAULT_SORT").filter(|sort| !sort.trim().is_empty()).cloned(),
+            maintenance_message: parse_maintenance_message(vars)?,
+            strict_query_params: parse_var(vars, "STRICT_QUERY_PARAMS", defaults.strict_query_params)?,
+            strict_region_lookup: parse_var(vars, "STRICT_REGION_LOOKUP", defaults.strict_region_lookup)?,
+            coalesce_queries: parse_var(vars, "COALESCE_QUERIES", defaults.coalesce_queries)?,
+            peak_ages: parse_positive_pairs(vars, "PEAK_AGES", "variety", "years")?.unwrap_or(defaults.peak_ages),
+            source_scales: parse_positive_pairs(vars, "SOURCE_SCALES", "source", "scale")?.unwrap_or_default(),
+            default_currency: parse_var(vars, "DEFAULT_CURRENCY", defaults.default_currency)?,
+            currency_rates: parse_positive_pairs(vars, "CURRENCY_RATES", "currency", "rate")?.unwrap_or_default(),
+            tenant_tokens: parse_tenant_tokens(vars)?,
+            anonymized_fields: parse_anonymized_fields(vars)?.unwrap_or(defaults.anonymized_fields),
+            query_aliases: parse_query_aliases(vars)?.unwrap_or(defaults.query_aliases),
+        };
+
+        if let Some(sort) = &config.default_sort
+            && order_by_clause(sort, NullsOrder::default()).is_err()
+        {
+            return Err(ConfigError {
+                key: "DEFAULT_SORT",
+                value: sort.clone(),
+                reason: format!("sort columns must be one of {}", SORTABLE_COLUMNS.join(", ")),
+            });
+        }
+
+        if !is_currency_code(&config.default_currency) {
+            return Err(ConfigError {
+                key: "DEFAULT_CURRENCY",
+                value: config.default_currency.clone(),
+                reason: "expected a three-letter ISO 4217 code such as USD".to_string(),
+            });
+        }
+        if let Some((currency, _)) = config.currency_rates.iter().find(|(currency, _)| !is_currency_code(currency)) {
+            return Err(ConfigError {
+                key: "CURRENCY_RATES",
+                value: currency.clone(),
+                reason: "expected a three-letter ISO 4217 code such as EUR".to_string(),
+            });
+        }
+
+        if let Some(max_page_size) = config.max_page_size.filter(|max| *max < 1) {
+            return Err(ConfigError {
+                key: "MAX_PAGE_SIZE",
+                value: max_page_size.to_string(),
+                reason: "must be at least 1".to_string(),
+            });
+        }
+        if config.max_search_results < 1 {
+            return Err(ConfigError {
+                key: "MAX_SEARCH_RESULTS",
+                value: config.max_search_results.to_string(),
+                reason: "must be at least 1".to_string(),
+            });
+        }
+        if config.rating_decimals > MAX_RATING_DECIMALS {
+            return Err(ConfigError {
+                key: "RATING_DECIMALS",
+                value: config.rating_decimals.to_string(),
+                reason: format!("must be at most {}", MAX_RATING_DECIMALS),
+            });
+        }
+        if config.max_connections == 0 {
+            return Err(ConfigError {
+                key: "DATABASE_MAX_CONNECTIONS",
+                value: config.max_connections.to_string(),
+                reason: "must be at least 1".to_string(),
+            });
+        }
+        if config.min_connections > config.max_connections {
+            return Err(ConfigError {
+                key: "DATABASE_MIN_CONNECTIONS",
+                value: config.min_connections.to_string(),
+                reason: format!("must not exceed DATABASE_MAX_CONNECTIONS ({})", config.max_connections),
+            });
+        }
+
+        Ok(config)
+    }
+
+    fn bind_address(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+
+    fn peak_age(&self, variety: Option<&str>) -> f64 {
+        variety
+            .and_then(|variety| {
+                self.peak_ages
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(variety))
+            })
+            .map_or(FALLBACK_PEAK_AGE, |(_, age)| *age)
+    }
+}
+
+fn parse_cors_origins(vars: &HashMap<String, String>) -> Result<Vec<HeaderValue>, ConfigError> {
+    let Some(value) = vars.get("CORS_ORIGINS") else {
+        return Ok(Vec::new());
+    };
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| {
+            origin.parse::<HeaderValue>().map_err(|err| ConfigError {
+                key: "CORS_ORIGINS",
+                value: origin.to_string(),
+                reason: err.to_string(),
+            })
+        })
+        .collect()
+}
+
+fn parse_maintenance_message(vars: &HashMap<String, String>) -> Result<Option<HeaderValue>, ConfigError> {
+    let Some(message) = vars.get("MAINTENANCE_MESSAGE").map(|message| message.trim()).filter(|message| !message.is_empty())
+    else {
+        return Ok(None);
+    };
+
+    message.parse::<HeaderValue>().map(Some).map_err(|err| ConfigError {
+        key: "MAINTENANCE_MESSAGE",
+        value: message.to_string(),
+        reason: err.to_string(),
+    })
+}
+
+// Parses comma-separated `name=number` pairs whose numbers must be positive.
+fn parse_positive_pairs(
+    vars: &HashMap<String, String>,
+    key: &'static str,
+    name_label: &str,
+    value_label: &str,
+) -> Result<Option<Vec<(String, f64)>>, ConfigError> {
+    let Some(value) = vars.get(key) else {
+        return Ok(None);
+    };
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let invalid = |reason: String| ConfigError {
+                key,
+                value: entry.to_string(),
+                reason,
+            };
+            let (name, number) = entry
+                .rsplit_once('=')
+                .ok_or_else(|| invalid(format!("expected {}={}", name_label, value_label)))?;
+            let number: f64 = number
+                .trim()
+                .parse()
+                .map_err(|_| invalid(format!("{} must be a number", value_label)))?;
+            if !number.is_finite() || number <= 0.0 {
+                return Err(invalid(format!("{} must be positive", value_label)));
             }
-            if let Some(variety) = &filters.variety {
-                conditions.push(format!("variety LIKE '%{}%'", variety.replace("'", "''")));
+            Ok((name.trim().to_string(), number))
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn parse_anonymized_fields(vars: &HashMap<String, String>) -> Result<Option<Vec<String>>, ConfigError> {
+    let Some(value) = vars.get("ANONYMIZE_FIELDS") else {
+        return Ok(None);
+    };
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(|field| {
+            let known = WINE_FIELDS.iter().any(|(name, _, _)| *name == field) || REVIEW_FIELDS.contains(&field);
+            if !known {
+                return Err(ConfigError {
+                    key: "ANONYMIZE_FIELDS",
+                    value: field.to_string(),
+                    reason: "must name a wine or review field".to_string(),
+                });
             }
-            if let Some(min_rating) = filters.min_rating {
-                conditions.push(format!("rating >= {}", min_rating));
+            Ok(field.to_string())
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn parse_query_aliases(vars: &HashMap<String, String>) -> Result<Option<Vec<(String, String)>>, ConfigError> {
+    let Some(value) = vars.get("QUERY_PARAM_ALIASES") else {
+        return Ok(None);
+    };
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let invalid = |reason: &str| ConfigError {
+                key: "QUERY_PARAM_ALIASES",
+                value: entry.to_string(),
+                reason: reason.to_string(),
+            };
+            let (alias, canonical) = entry.split_once('=').ok_or_else(|| invalid("expected alias=parameter"))?;
+            let (alias, canonical) = (alias.trim(), canonical.trim());
+            if alias.is_empty() || WINE_FILTER_PARAMS.contains(&alias) {
+                return Err(invalid("alias must be a name that is not already a /wines parameter"));
             }
-            if let Some(max_rating) = filters.max_rating {
-                conditions.push(format!("rating <= {}", max_rating));
+            if !WINE_FILTER_PARAMS.contains(&canonical) {
+                return Err(invalid("must map to a /wines query parameter"));
             }
-            
-            if !conditions.is_empty() {
-                query.push_str(" WHERE ");
-                query.push_str(&conditions.join(" AND "));
+            Ok((alias.to_string(), canonical.to_string()))
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn parse_tenant_tokens(vars: &HashMap<String, String>) -> Result<Vec<(String, String)>, ConfigError> {
+    let Some(value) = vars.get("TENANT_TOKENS") else {
+        return Ok(Vec::new());
+    };
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let invalid = |reason: &str| ConfigError {
+                key: "TENANT_TOKENS",
+                // Only the tenant half is echoed so tokens never end up in startup logs.
+                value: entry.split_once('=').map_or("", |(_, tenant)| tenant).to_string(),
+                reason: reason.to_string(),
+            };
+            let (token, tenant) = entry.split_once('=').ok_or_else(|| invalid("expected token=tenant"))?;
+            let (token, tenant) = (token.trim(), tenant.trim());
+            if token.is_empty() {
+                return Err(invalid("token must not be empty"));
             }
-            
-            let wines = sqlx::query_as::<_, Wine>(&query)
-                .fetch_all(&pool)
-                .await
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-            
-            Ok(Json(wines))
+            if !is_valid_identifier(tenant) {
+                return Err(invalid("tenant must be 1-64 letters, digits, '-' or '_'"));
+            }
+            Ok((token.to_string(), tenant.to_string()))
+        })
+        .collect()
+}
+
+async fn connect_pool(config: &Config) -> Result<SqlitePool, sqlx::Error> {
+    // WAL lets readers proceed while a write is in progress, and synchronous=NORMAL is the
+    // durability level SQLite recommends with WAL (a power loss can drop the latest commits
+    // but never corrupts the database). In-memory databases ignore the journal mode.
+    let options = SqliteConnectOptions::from_str(&config.database_url)?
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .statement_cache_capacity(config.statement_cache_capacity)
+        .busy_timeout(config.busy_timeout);
+
+    SqlitePoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .connect_with(options)
+        .await
+}
+
+async fn run_migrations(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS source_scales (
+            source TEXT PRIMARY KEY COLLATE NOCASE,
+            scale REAL NOT NULL
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS region_aliases (
+            alias TEXT PRIMARY KEY COLLATE NOCASE,
+            canonical TEXT NOT NULL
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS reviews (
+            id INTEGER PRIMARY KEY,
+            wine_id INTEGER NOT NULL REFERENCES wine_ratings(id),
+            reviewer TEXT,
+            score REAL,
+            comment TEXT,
+            created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_reviews_wine_id ON reviews (wine_id)")
+        .execute(pool)
+        .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS wine_tags (
+            wine_id INTEGER NOT NULL REFERENCES wine_ratings(id),
+            tag TEXT NOT NULL,
+            PRIMARY KEY (wine_id, tag)
+        )",
+    )
+    .execute(pool)
+    .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_wine_tags_tag ON wine_tags (tag)")
+        .execute(pool)
+        .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS search_log (
+            id INTEGER PRIMARY KEY,
+            term TEXT NOT NULL,
+            searched_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+    )
+    .execute(pool)
+    .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_search_log_searched_at ON search_log (searched_at)")
+        .execute(pool)
+        .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS favorites (
+            tenant_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            wine_id INTEGER NOT NULL REFERENCES wine_ratings(id),
+            created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (tenant_id, user_id, wine_id)
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS saved_filters (
+            id TEXT PRIMARY KEY,
+            filters TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    add_column_if_missing(pool, "wine_ratings", "vintage", "INTEGER").await?;
+    add_column_if_missing(pool, "wine_ratings", "image_url", "TEXT").await?;
+    add_column_if_missing(pool, "wine_ratings", "latitude", "REAL").await?;
+    add_column_if_missing(pool, "wine_ratings", "longitude", "REAL").await?;
+    add_column_if_missing(pool, "wine_ratings", "price", "REAL").await?;
+    add_column_if_missing(pool, "wine_ratings", "source", "TEXT").await?;
+    add_column_if_missing(pool, "wine_ratings", "currency", "TEXT").await?;
+    add_column_if_missing(pool, "wine_ratings", "name_search", "TEXT").await?;
+    add_column_if_missing(pool, "wine_ratings", "notes_search", "TEXT").await?;
+    add_column_if_missing(pool, "reviews", "comment_search", "TEXT").await?;
+    add_column_if_missing(pool, "wine_ratings", "version", "INTEGER NOT NULL DEFAULT 1").await?;
+    add_column_if_missing(pool, "wine_ratings", "external_id", "TEXT").await?;
+    let tenant_column = format!("TEXT NOT NULL DEFAULT '{}'", DEFAULT_TENANT);
+    for table in ["wine_ratings", "saved_filters", "search_log"] {
+        add_column_if_missing(pool, table, "tenant_id", &tenant_column).await?;
+    }
+    if table_exists(pool, "wine_ratings").await? {
+        for index in LEGACY_INDEXES {
+            sqlx::query(&format!("DROP INDEX IF EXISTS {}", index)).execute(pool).await?;
         }
+        sqlx::query(
+            "CREATE UNIQUE INDEX IF NOT EXISTS idx_wine_ratings_tenant_external_id
+            ON wine_ratings (tenant_id, external_id)",
+        )
+        .execute(pool)
+        .await?;
+        for (index, columns) in FILTER_INDEXES {
+            sqlx::query(&format!("CREATE INDEX IF NOT EXISTS {} ON wine_ratings ({})", index, columns))
+                .execute(pool)
+                .await?;
+        }
+    }
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS dataset_version (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            version INTEGER NOT NULL
+        )",
+    )
+    .execute(pool)
+    .await?;
+    backfill_search_text(pool).await?;
+
+    sqlx::query("INSERT OR IGNORE INTO dataset_version (id, version) VALUES (1, 0)")
+        .execute(pool)
+        .await?;
+
+    for table in VERSIONED_TABLES {
+        if !table_exists(pool, table).await? {
+            continue;
+        }
+        for operation in ["INSERT", "UPDATE", "DELETE"] {
+            sqlx::query(&format!(
+                "CREATE TRIGGER IF NOT EXISTS bump_dataset_version_{table}_{op} AFTER {operation} ON {table}
+                BEGIN UPDATE dataset_version SET version = version + 1; END",
+                table = table,
+                op = operation.to_lowercase(),
+                operation = operation,
+            ))
+            .execute(pool)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+// SQLite cannot fold accents itself, so searchable text is folded in Rust and stored next to the
+// original. API writes keep it current; rows predating the columns or written by hand are filled
+// in here, and until then search falls back to LOWER() of the original text.
+async fn backfill_search_text(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    if table_exists(pool, "wine_ratings").await? {
+        let stale = sqlx::query_as::<_, (i64, String, Option<String>)>(
+            "SELECT id, name, notes FROM wine_ratings
+            WHERE name_search IS NULL OR (notes IS NOT NULL AND notes_search IS NULL)",
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for (id, name, notes) in stale {
+            sqlx::query("UPDATE wine_ratings SET name_search = ?, notes_search = ? WHERE id = ?")
+                .bind(fold_search_text(&name))
+                .bind(notes.as_deref().map(fold_search_text))
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+    let stale = sqlx::query_as::<_, (i64, String)>(
+        "SELECT id, comment FROM reviews WHERE comment IS NOT NULL AND comment_search IS NULL",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for (id, comment) in stale {
+        sqlx::query("UPDATE reviews SET comment_search = ? WHERE id = ?")
+            .bind(fold_search_text(&comment))
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await
+}
+
+async fn table_exists(pool: &SqlitePool, table: &str) -> Result<bool, sqlx::Error> {
+    let count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+        .bind(table)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(count > 0)
+}
+
+async fn fetch_dataset_version(pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar("SELECT version FROM dataset_version WHERE id = 1")
+        .fetch_one(pool)
+        .await
+}
+
+async fn add_column_if_missing(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlx::Error> {
+    if !table_exists(pool, table).await? {
+        return Ok(());
+    }
+
+    let columns = sqlx::query_scalar::<_, String>("SELECT name FROM pragma_table_info(?)")
+        .bind(table)
+        .fetch_all(pool)
+        .await?;
+    if !columns.iter().any(|name| name == column) {
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+            .execute(pool)
+            .await?;
+    }
+
+    Ok(())
+}
+
+// Rows that already share a name and vintage would make CREATE UNIQUE INDEX fail, so the
+// index is skipped with a warning naming them rather than keeping the server from starting.
+async fn set_unique_wine_names(pool: &SqlitePool, enabled: bool) -> Result<(), sqlx::Error> {
+    if enabled {
+        let collisions = sqlx::query_as::<_, (String, String, Option<i64>, i64)>(
+            "SELECT tenant_id, name, vintage, COUNT(*) FROM wine_ratings
+            GROUP BY tenant_id, name, IFNULL(vintage, 0) HAVING COUNT(*) > 1 ORDER BY tenant_id, name",
+        )
+        .fetch_all(pool)
+        .await?;
+        for (tenant, name, vintage, count) in &collisions {
+            tracing::warn!(
+                "UNIQUE_WINE_NAMES: tenant {:?} has {} wines named {:?} with vintage {:?}; unique index not created",
+                tenant,
+                count,
+                name,
+                vintage
+            );
+        }
+        if !collisions.is_empty() {
+            return Ok(());
+        }
+    }
+    let statement = if enabled {
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_wine_ratings_tenant_name_vintage
+        ON wine_ratings (tenant_id, name, IFNULL(vintage, 0))"
+    } else {
+        "DROP INDEX IF EXISTS idx_wine_ratings_tenant_name_vintage"
+    };
+    sqlx::query(statement).execute(pool).await?;
+
+    Ok(())
+}
+
+// The scales live in a table so every wine query can normalize ratings in SQL.
+async fn set_source_scales(pool: &SqlitePool, scales: &[(String, f64)]) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM source_scales").execute(&mut *tx).await?;
+    for (source, scale) in scales {
+        sqlx::query("INSERT OR REPLACE INTO source_scales (source, scale) VALUES (?, ?)")
+            .bind(source)
+            .bind(scale)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await
+}
+
+async fn normalize_region<'e, E>(executor: E, region: Option<&str>) -> Result<Option<String>, sqlx::Error>
+where
+    E: sqlx::SqliteExecutor<'e>,
+{
+    let Some(region) = region.map(str::trim).filter(|region| !region.is_empty()) else {
+        return Ok(None);
+    };
+
+    // An alias wins over a differently-cased spelling of a canonical region.
+    let canonical = sqlx::query_scalar::<_, String>(
+        "SELECT canonical FROM region_aliases WHERE alias = ?1 OR canonical = ?1 COLLATE NOCASE
+        ORDER BY alias = ?1 DESC LIMIT 1",
+    )
+    .bind(region)
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(Some(canonical.unwrap_or_else(|| region.to_string())))
+}
+
+// SQLITE_BUSY and SQLITE_LOCKED (in their extended forms too) mean another writer held the
+// database for longer than the busy timeout; that is worth retrying, unlike other failures.
+fn is_transient(err: &sqlx::Error) -> bool {
+    err.as_database_error()
+        .and_then(|err| err.code())
+        .and_then(|code| code.parse::<i32>().ok())
+        .is_some_and(|code| matches!(code & 0xff, 5 | 6))
+}
+
+// Runs the operation again after transient errors, doubling the delay each time; the whole
+// operation is repeated, so a transaction inside it restarts from scratch.
+async fn retry_transient<T, F, Fut>(config: &Config, mut operation: F) -> Result<T, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut delay = config.write_retry_backoff;
+    let mut retries = 0;
+    loop {
+        match operation().await {
+            Err(err) if retries < config.write_retries && is_transient(&err) => {
+                retries += 1;
+                tracing::debug!("retrying transient database error (attempt {}): {}", retries, err);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn database_error(err: sqlx::Error) -> StatusCode {
+    if is_transient(&err) {
+        tracing::warn!("database locked: {}", err);
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+fn content_type_essence(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+fn is_json_content_type(content_type: &str) -> bool {
+    let essence = content_type_essence(content_type);
+    essence == "application/json" || (essence.starts_with("application/") && essence.ends_with("+json"))
+}
+
+fn is_csv_content_type(content_type: &str) -> bool {
+    content_type_essence(content_type) == "text/csv"
+}
+
+fn has_request_body(request: &Request) -> bool {
+    use axum::body::HttpBody;
+    request.body().size_hint().upper() != Some(0)
+}
+
+// Renames aliased query parameters to their canonical names before any handler parses the
+// query, so filters, strict-mode checks and coalescing keys only ever see canonical names.
+async fn apply_query_aliases(
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(query) = request.uri().query() else {
+        return next.run(request).await;
+    };
+    let renamed = query
+        .split('&')
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').map_or((pair, None), |(key, value)| (key, Some(value)));
+            let key = config
+                .query_aliases
+                .iter()
+                .find(|(alias, _)| alias == key)
+                .map_or(key, |(_, canonical)| canonical.as_str());
+            match value {
+                Some(value) => format!("{}={}", key, value),
+                None => key.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    if renamed != query {
+        let uri = format!("{}?{}", request.uri().path(), renamed);
+        if let Ok(uri) = uri.parse() {
+            *request.uri_mut() = uri;
+        }
+    }
+
+    next.run(request).await
+}
+
+async fn require_body_content_type(request: Request, next: Next) -> Response {
+    let is_write = matches!(*request.method(), axum::http::Method::POST | axum::http::Method::PUT | axum::http::Method::PATCH);
+    // Bodyless writes such as POST /admin/optimize carry no payload to check.
+    if is_write && has_request_body(&request) {
+        let content_type = request.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+        let (accepted, expected) = if CSV_BODY_ROUTES.contains(&request.uri().path()) {
+            (content_type.is_some_and(is_csv_content_type), "text/csv")
+        } else {
+            (content_type.is_some_and(is_json_content_type), "application/json")
+        };
+        if !accepted {
+            let error = ErrorMessage {
+                error: format!(
+                    "expected Content-Type: {}, got {}",
+                    expected,
+                    content_type.unwrap_or("no content type")
+                ),
+            };
+            return (StatusCode::UNSUPPORTED_MEDIA_TYPE, Json(error)).into_response();
+        }
+    }
+    next.run(request).await
+}
+
+fn prefers_minimal_return(request: &Request) -> bool {
+    request
+        .headers()
+        .get_all(PREFER_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|preference| preference.trim().eq_ignore_ascii_case("return=minimal"))
+}
+
+// Prefer: return=minimal (RFC 7240) drops the body of a successful write; the status and
+// Location header still tell the client what happened and where the wine lives.
+async fn apply_return_preference(request: Request, next: Next) -> Response {
+    let minimal = prefers_minimal_return(&request);
+    let response = next.run(request).await;
+    if !minimal || !response.status().is_success() {
+        return response;
+    }
+
+    let (mut parts, _) = response.into_parts();
+    if parts.status == StatusCode::OK {
+        parts.status = StatusCode::NO_CONTENT;
+    }
+    parts.headers.remove(header::CONTENT_TYPE);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(PREFERENCE_APPLIED_HEADER, HeaderValue::from_static("return=minimal"));
+    Response::from_parts(parts, axum::body::Body::empty())
+}
+
+// Informational only: the request is served as usual, the notice rides along on every response.
+async fn add_maintenance_notice(
+    axum::extract::State(notice): axum::extract::State<Option<HeaderValue>>,
+    mut response: Response,
+) -> Response {
+    if let Some(notice) = notice {
+        response.headers_mut().insert(MAINTENANCE_NOTICE_HEADER, notice);
+    }
+    response
+}
+
+async fn add_retry_after(mut response: Response) -> Response {
+    if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECONDS));
+    }
+    response
+}
+
+fn round_rating(value: f64, decimals: u32) -> f64 {
+    let factor = 10f64.powi(decimals as i32);
+    (value * factor).round() / factor
+}
+
+fn is_valid_rating(rating: f64) -> bool {
+    (MIN_RATING..=MAX_RATING).contains(&rating)
+}
+
+fn is_valid_coordinates(latitude: Option<f64>, longitude: Option<f64>) -> bool {
+    latitude.is_none_or(|lat| (-90.0..=90.0).contains(&lat)) && longitude.is_none_or(|lng| (-180.0..=180.0).contains(&lng))
+}
+
+fn is_currency_code(code: &str) -> bool {
+    code.len() == 3 && code.bytes().all(|byte| byte.is_ascii_uppercase())
+}
+
+// CURRENCY_RATES are units per one DEFAULT_CURRENCY, so any priced currency converts to any
+// other through the default; prices without a currency are taken to be in the default.
+fn convert_price(config: &Config, price: f64, from: Option<&str>, to: &str) -> Option<f64> {
+    let rate = |currency: &str| {
+        if currency.eq_ignore_ascii_case(&config.default_currency) {
+            return Some(1.0);
+        }
+        config
+            .currency_rates
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(currency))
+            .map(|(_, rate)| *rate)
+    };
+    let converted = price / rate(from.unwrap_or(&config.default_currency))? * rate(to)?;
+    Some((converted * 100.0).round() / 100.0)
+}
+
+// A missing rate leaves price_converted null rather than failing the read; price stays as stored.
+fn apply_display_currency(wines: &mut [Wine], config: &Config, display_currency: Option<&str>) {
+    let Some(display_currency) = display_currency.map(str::trim).filter(|currency| !currency.is_empty()) else {
+        return;
+    };
+    for wine in wines {
+        wine.price_converted = wine
+            .price
+            .and_then(|price| convert_price(config, price, wine.currency.as_deref(), display_currency));
+    }
+}
+
+fn is_http_url(url: &str) -> bool {
+    reqwest::Url::parse(url)
+        .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.host_str().is_some())
+}
+
+fn push_condition<'a, 'args>(
+    builder: &'a mut QueryBuilder<'args, Sqlite>,
+    first: &mut bool,
+) -> &'a mut QueryBuilder<'args, Sqlite> {
+    builder.push(if std::mem::take(first) { " WHERE " } else { " AND " })
+}
+
+fn push_filter_conditions(builder: &mut QueryBuilder<'_, Sqlite>, tenant: &Tenant, filters: &WineFilters) {
+    let mut first = true;
+
+    push_condition(builder, &mut first).push("tenant_id = ").push_bind(tenant.0.clone());
+
+    if let Some(q) = filters.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+        let pattern = format!("%{}%", q);
+        push_condition(builder, &mut first)
+            .push("(name LIKE ")
+            .push_bind(pattern.clone())
+            .push(" OR notes LIKE ")
+            .push_bind(pattern)
+            .push(")");
+    }
+    if let Some(region) = &filters.region {
+        push_condition(builder, &mut first)
+            .push("region LIKE ")
+            .push_bind(format!("%{}%", region));
+    }
+    if let Some(region) = &filters.region_exact {
+        push_condition(builder, &mut first).push("region = ").push_bind(region.clone());
+    }
+    if let Some(exclude_region) = &filters.exclude_region {
+        push_condition(builder, &mut first)
+            .push("(region IS NULL OR region NOT LIKE ")
+            .push_bind(format!("%{}%", exclude_region))
+            .push(")");
+    }
+    if let Some(variety) = &filters.variety {
+        let varieties: Vec<&str> = variety.split(',').map(str::trim).filter(|v| !v.is_empty()).collect();
+        if !varieties.is_empty() {
+            push_condition(builder, &mut first).push("(");
+            for (index, variety) in varieties.iter().enumerate() {
+                if index > 0 {
+                    builder.push(" OR ");
+                }
+                builder.push("variety LIKE ").push_bind(format!("%{}%", variety));
+            }
+            builder.push(")");
+        }
+    }
+    if let Some(variety) = &filters.variety_exact {
+        push_condition(builder, &mut first).push("variety = ").push_bind(variety.clone());
+    }
+    if let Some(min_rating) = filters.min_rating {
+        push_condition(builder, &mut first)
+            .push(NORMALIZED_RATING)
+            .push(" >= ")
+            .push_bind(min_rating);
+    }
+    if let Some(max_rating) = filters.max_rating {
+        push_condition(builder, &mut first)
+            .push(NORMALIZED_RATING)
+            .push(" <= ")
+            .push_bind(max_rating);
+    }
+    match filters.has_notes {
+        Some(true) => {
+            push_condition(builder, &mut first).push("(notes IS NOT NULL AND TRIM(notes) != '')");
+        }
+        Some(false) => {
+            push_condition(builder, &mut first).push("(notes IS NULL OR TRIM(notes) = '')");
+        }
+        None => {}
+    }
+    // Every wine has at least zero reviews, so 0 leaves the results alone.
+    if let Some(min_reviews) = filters.min_reviews.filter(|min_reviews| *min_reviews > 0) {
+        push_condition(builder, &mut first)
+            .push("id IN (SELECT wine_id FROM reviews GROUP BY wine_id HAVING COUNT(*) >= ")
+            .push_bind(min_reviews)
+            .push(")");
+    }
+    if let Some(grade) = filters.grade {
+        let (lower, upper) = grade.rating_range();
+        if let Some(lower) = lower {
+            push_condition(builder, &mut first)
+                .push(NORMALIZED_RATING)
+                .push(" >= ")
+                .push_bind(lower);
+        }
+        if let Some(upper) = upper {
+            push_condition(builder, &mut first)
+                .push(NORMALIZED_RATING)
+                .push(" < ")
+                .push_bind(upper);
+        }
+    }
+    if let Some(above) = filters.above_average {
+        // Both sides are on the 100-point scale, so a 19/20 rating compares as 95 rather than 19.
+        push_condition(builder, &mut first)
+            .push(NORMALIZED_RATING)
+            .push(if above { " > " } else { " <= " })
+            .push("(SELECT AVG(")
+            .push(NORMALIZED_RATING)
+            .push(") FROM wine_ratings WHERE tenant_id = ")
+            .push_bind(tenant.0.clone())
+            .push(")");
+    }
+    if filters.distinct_names == Some(true) {
+        // Keep the highest-rated of each normalized name among the wines the other filters match.
+        push_condition(builder, &mut first).push(concat!(
+            "id IN (SELECT id FROM (SELECT id, ROW_NUMBER() OVER (
+                PARTITION BY LOWER(TRIM(name)) ORDER BY rating IS NULL, ",
+            normalized_rating!(),
+            " DESC, id
+            ) AS position FROM wine_ratings",
+        ));
+        let others = WineFilters {
+            distinct_names: None,
+            ..filters.clone()
+        };
+        push_filter_conditions(builder, tenant, &others);
+        builder.push(") WHERE position = 1)");
+    }
+}
+
+fn filtered_query(
+    base_query: &str,
+    tenant: &Tenant,
+    filters: &WineFilters,
+) -> Result<QueryBuilder<'static, Sqlite>, StatusCode> {
+    let mut builder = QueryBuilder::new(base_query);
+
+    push_filter_conditions(&mut builder, tenant, filters);
+    builder.push(order_by_clause(
+        filters.sort.as_deref().unwrap_or_default(),
+        filters.nulls.unwrap_or_default(),
+    )?);
+
+    Ok(builder)
+}
+
+fn count_query(tenant: &Tenant, filters: &WineFilters) -> QueryBuilder<'static, Sqlite> {
+    let mut builder = QueryBuilder::new("SELECT COUNT(*) FROM wine_ratings");
+    push_filter_conditions(&mut builder, tenant, filters);
+    builder
+}
+
+// Without a limit /wines serves every matching row, or MAX_PAGE_SIZE rows when that is set.
+fn page_bounds(limit: Option<i64>, offset: Option<i64>, config: &Config) -> Result<(Option<i64>, i64), PageError> {
+    let offset = offset.unwrap_or(0);
+    if limit.is_some_and(|limit| limit < 1) || offset < 0 {
+        return Err(PageError::Invalid);
+    }
+    let Some(max) = config.max_page_size else {
+        return Ok((limit, offset));
+    };
+    if limit.is_some_and(|limit| limit > max) && config.page_size_mode == PageSizeMode::Reject {
+        return Err(PageError::TooLarge { max });
+    }
+
+    Ok((Some(limit.map_or(max, |limit| limit.min(max))), offset))
+}
+
+// Search pages default to DEFAULT_PAGE_SIZE matches instead of returning them all.
+fn search_page_bounds(limit: Option<i64>, offset: Option<i64>, config: &Config) -> Result<(i64, i64), PageError> {
+    let default = config.max_page_size.map_or(DEFAULT_PAGE_SIZE, |max| max.min(DEFAULT_PAGE_SIZE));
+    let (limit, offset) = page_bounds(Some(limit.unwrap_or(default)), offset, config)?;
+
+    Ok((limit.unwrap_or(default), offset))
+}
+
+fn push_page(builder: &mut QueryBuilder<'_, Sqlite>, (limit, offset): (Option<i64>, i64)) {
+    // SQLite treats a negative LIMIT as no limit at all.
+    builder.push(" LIMIT ").push_bind(limit.unwrap_or(-1));
+    builder.push(" OFFSET ").push_bind(offset);
+}
+
+fn with_total_count(mut response: Response, total: i64) -> Response {
+    response.headers_mut().insert(TOTAL_COUNT_HEADER, HeaderValue::from(total));
+    response
+}
+
+// Search bodies stay a bare JSON array; the cap and any spelling suggestion travel as headers.
+fn search_response<T: Serialize>(results: Vec<T>, total: i64, truncated: bool, suggestion: Option<&str>) -> Response {
+    let mut response = with_total_count(Json(results).into_response(), total);
+    if truncated {
+        response.headers_mut().insert(SEARCH_TRUNCATED_HEADER, HeaderValue::from_static("true"));
+    }
+    if let Some(value) = suggestion.and_then(|name| HeaderValue::from_bytes(name.as_bytes()).ok()) {
+        response.headers_mut().insert(SEARCH_SUGGESTION_HEADER, value);
+    }
+    response
+}
+
+async fn get_dataset_version(
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<DatasetVersion>, StatusCode> {
+    let version = fetch_dataset_version(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(DatasetVersion { version }))
+}
+
+async fn add_dataset_version(
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    if response.status().is_success()
+        && let Ok(version) = fetch_dataset_version(&pool).await
+    {
+        response.headers_mut().insert(DATASET_VERSION_HEADER, HeaderValue::from(version));
+    }
+
+    response
+}
+
+const SORTABLE_COLUMNS: &[&str] = &["id", "name", "region", "variety", "rating"];
+
+fn is_nullable_column(column: &str) -> bool {
+    WINE_FIELDS
+        .iter()
+        .any(|(name, _, nullable)| *name == column && *nullable)
+}
+
+fn order_by_clause(sort: &str, nulls: NullsOrder) -> Result<String, StatusCode> {
+    let mut keys = Vec::new();
+
+    for key in sort.split(',').map(str::trim).filter(|key| !key.is_empty()) {
+        let (column, direction) = match key.strip_prefix('-') {
+            Some(column) => (column, "DESC"),
+            None => (key, "ASC"),
+        };
+        if !SORTABLE_COLUMNS.contains(&column) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        if is_nullable_column(column) {
+            match nulls {
+                NullsOrder::First => keys.push(format!("{} IS NULL DESC", column)),
+                NullsOrder::Last => keys.push(format!("{} IS NULL", column)),
+            }
+        }
+        keys.push(format!("{} {}", column, direction));
+    }
+    keys.push("id".to_string());
+
+    Ok(format!(" ORDER BY {}", keys.join(", ")))
+}
+
+fn list_response<T: Serialize>(items: Vec<T>, format: Option<ListFormat>) -> Result<Response, StatusCode> {
+    match format.unwrap_or_default() {
+        ListFormat::Json => Ok(Json(items).into_response()),
+        ListFormat::Ndjson => {
+            let mut body = String::new();
+            for item in &items {
+                body.push_str(&serde_json::to_string(item).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?);
+                body.push('\n');
+            }
+            Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response())
+        }
+    }
+}
+
+fn unknown_params(params: &[(String, String)], known: &[&str]) -> Vec<String> {
+    let mut unknown: Vec<String> = params
+        .iter()
+        .map(|(key, _)| key)
+        .filter(|key| !known.contains(&key.as_str()))
+        .cloned()
+        .collect();
+    unknown.sort();
+    unknown.dedup();
+    unknown
+}
+
+async fn get_wines(
+    Query(mut filters): Query<WineFilters>,
+    Query(params): Query<Vec<(String, String)>>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+    axum::extract::State(reviews): axum::extract::State<ReviewLoader>,
+    axum::extract::State(wine_queries): axum::extract::State<WineQueries>,
+) -> Result<Response, StatusCode> {
+    if filters.strict.unwrap_or(config.strict_query_params) {
+        let unknown = unknown_params(&params, WINE_FILTER_PARAMS);
+        if !unknown.is_empty() {
+            let error = UnknownParamsError {
+                error: format!("unknown query parameters: {}", unknown.join(", ")),
+                unknown,
+            };
+            return Ok((StatusCode::BAD_REQUEST, Json(error)).into_response());
+        }
+    }
+    if filters.sort.is_none() {
+        filters.sort = config.default_sort.clone();
+    }
+
+    let page = match page_bounds(filters.limit, filters.offset, &config) {
+        Ok(page) => page,
+        Err(err) => return Ok(err.into_response()),
+    };
+
+    if filters.ids_only.unwrap_or(false) {
+        let total = count_query(&tenant, &filters)
+            .build_query_scalar::<i64>()
+            .fetch_one(&pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let mut query = filtered_query("SELECT id FROM wine_ratings", &tenant, &filters)?;
+        push_page(&mut query, page);
+        let ids = query
+            .build_query_scalar::<i64>()
+            .fetch_all(&pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Ok(with_total_count(list_response(ids, filters.format)?, total));
+    }
+
+    if filters.notes_length == Some(0) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let (total, mut wines) = wine_queries.fetch(&tenant, &filters, page).await?;
+    if let Some(max_chars) = filters.notes_length {
+        for wine in &mut wines {
+            wine.notes = wine.notes.as_deref().map(|notes| truncate_notes(notes, max_chars));
+        }
+    }
+    apply_display_currency(&mut wines, &config, filters.display_currency.as_deref());
+
+    if filters.include_reviews.unwrap_or(false) {
+        let wines = reviews
+            .attach(wines)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Ok(with_total_count(list_response(wines, filters.format)?, total));
+    }
+
+    Ok(with_total_count(list_response(wines, filters.format)?, total))
+}
+
+async fn facet_counts(
+    pool: &SqlitePool,
+    tenant: &Tenant,
+    column: &str,
+    filters: &WineFilters,
+) -> Result<BTreeMap<String, i64>, StatusCode> {
+    let mut query = QueryBuilder::new(format!("SELECT {column}, COUNT(*) FROM wine_ratings"));
+    push_filter_conditions(&mut query, tenant, filters);
+    query.push(format!(" AND {column} IS NOT NULL GROUP BY {column}"));
+    let counts = query
+        .build_query_as::<(String, i64)>()
+        .fetch_all(pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(counts.into_iter().collect())
+}
+
+// Each facet is counted with every filter except its own, so the number next to an option is
+// how many wines the list would show if that option were picked instead.
+async fn get_wine_facets(
+    Query(filters): Query<WineFilters>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<WineFacets>, StatusCode> {
+    let without_region = WineFilters {
+        region: None,
+        region_exact: None,
+        exclude_region: None,
+        ..filters.clone()
+    };
+    let without_variety = WineFilters {
+        variety: None,
+        variety_exact: None,
+        ..filters
+    };
+
+    Ok(Json(WineFacets {
+        region: facet_counts(&pool, &tenant, "region", &without_region).await?,
+        variety: facet_counts(&pool, &tenant, "variety", &without_variety).await?,
+    }))
+}
+
+// Counts characters rather than bytes so multi-byte text is never cut mid-character;
+// the ellipsis is included in max_chars.
+fn truncate_notes(notes: &str, max_chars: usize) -> String {
+    if notes.chars().count() <= max_chars {
+        return notes.to_string();
+    }
+    let kept: String = notes.chars().take(max_chars - 1).collect();
+    format!("{}…", kept.trim_end())
+}
+
+async fn fetch_filtered_wines(
+    pool: &SqlitePool,
+    tenant: &Tenant,
+    filters: &WineFilters,
+    page: (Option<i64>, i64),
+) -> Result<Vec<Wine>, StatusCode> {
+    let mut query = filtered_query(&format!("SELECT {} FROM wine_ratings", WINE_COLUMNS), tenant, filters)?;
+    push_page(&mut query, page);
+    query
+        .build_query_as::<Wine>()
+        .fetch_all(pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn new_saved_filter_id(rng: &mut impl Rng) -> String {
+    rng.sample_iter(&Alphanumeric)
+        .take(SAVED_FILTER_ID_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+async fn create_saved_filter(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+    Json(filters): Json<WineFilters>,
+) -> Result<Response, StatusCode> {
+    order_by_clause(filters.sort.as_deref().unwrap_or_default(), filters.nulls.unwrap_or_default())?;
+    if let Err(err) = page_bounds(filters.limit, filters.offset, &config) {
+        return Ok(err.into_response());
+    }
+
+    let id = new_saved_filter_id(&mut rand::thread_rng());
+    let stored = serde_json::to_string(&filters).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    sqlx::query("INSERT INTO saved_filters (id, tenant_id, filters) VALUES (?, ?, ?)")
+        .bind(&id)
+        .bind(&tenant.0)
+        .bind(stored)
+        .execute(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok((StatusCode::CREATED, Json(SavedFilter { id })).into_response())
+}
+
+async fn get_saved_filter_wines(
+    Path(id): Path<String>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> Result<Response, StatusCode> {
+    let stored = sqlx::query_scalar::<_, String>("SELECT filters FROM saved_filters WHERE id = ? AND tenant_id = ?")
+        .bind(id)
+        .bind(&tenant.0)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let filters: WineFilters = serde_json::from_str(&stored).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let page = match page_bounds(filters.limit, filters.offset, &config) {
+        Ok(page) => page,
+        Err(err) => return Ok(err.into_response()),
+    };
+    let wines = fetch_filtered_wines(&pool, &tenant, &filters, page).await?;
+
+    Ok(Json(wines).into_response())
+}
+
+async fn fetch_wine(pool: &SqlitePool, tenant: &Tenant, id: i64) -> Result<Option<Wine>, sqlx::Error> {
+    sqlx::query_as::<_, Wine>(&format!(
+        "SELECT {} FROM wine_ratings WHERE tenant_id = ? AND id = ?",
+        WINE_COLUMNS
+    ))
+    .bind(&tenant.0)
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+async fn get_wine(
+    tenant: Tenant,
+    Path(id): Path<i64>,
+    Query(display): Query<DisplayCurrencyQuery>,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> Result<Response, StatusCode> {
+    let mut wine = fetch_wine(&pool, &tenant, id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    apply_display_currency(std::slice::from_mut(&mut wine), &config, display.display_currency.as_deref());
+
+    Ok(([(header::ETAG, version_etag(wine.version))], Json(wine)).into_response())
+}
+
+async fn create_review(
+    tenant: Tenant,
+    Path(id): Path<i64>,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+    Json(review): Json<NewReview>,
+) -> Result<Response, StatusCode> {
+    fetch_wine(&pool, &tenant, id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let errors = review.validation_errors();
+    if !errors.is_empty() {
+        return Ok(validation_failed(errors));
+    }
+
+    let review = retry_transient(&config, || {
+        sqlx::query_as::<_, Review>(
+            "INSERT INTO reviews (wine_id, reviewer, score, comment_search, comment) VALUES (?, ?, ?, ?, ?)
+            RETURNING id, wine_id, reviewer, score, comment, created_at",
+        )
+        .bind(id)
+        .bind(&review.reviewer)
+        .bind(review.score)
+        .bind(review.comment.as_deref().map(fold_search_text))
+        .bind(&review.comment)
+        .fetch_one(&pool)
+    })
+    .await
+    .map_err(database_error)?;
+
+    Ok((StatusCode::CREATED, Json(review)).into_response())
+}
+
+async fn get_rating_timeline(
+    tenant: Tenant,
+    Path(id): Path<i64>,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<Vec<RatingPoint>>, StatusCode> {
+    fetch_wine(&pool, &tenant, id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let points = sqlx::query_as::<_, RatingPoint>(
+        "SELECT created_at, score FROM reviews WHERE wine_id = ? AND score IS NOT NULL ORDER BY created_at, id",
+    )
+    .bind(id)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(points))
+}
+
+async fn export_wine(
+    tenant: Tenant,
+    Path(id): Path<i64>,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Response, StatusCode> {
+    let wine = fetch_wine(&pool, &tenant, id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let body = serde_json::to_string_pretty(&wine).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"wine-{}.json\"", id)),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+// Removes the fields wherever they appear, so a stripped "reviewer" also leaves nested reviews.
+fn strip_fields(value: &mut serde_json::Value, fields: &[String]) {
+    match value {
+        serde_json::Value::Object(object) => {
+            object.retain(|key, _| !fields.contains(key));
+            object.values_mut().for_each(|value| strip_fields(value, fields));
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(|value| strip_fields(value, fields)),
+        _ => {}
+    }
+}
+
+fn wines_to_csv(wines: &[serde_json::Value], stripped: &[String]) -> Result<Vec<u8>, csv::Error> {
+    let columns: Vec<&str> = WINE_FIELDS
+        .iter()
+        .map(|(name, _, _)| *name)
+        .filter(|name| !stripped.iter().any(|field| field == name))
+        .collect();
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(&columns)?;
+    for wine in wines {
+        writer.write_record(columns.iter().map(|column| match &wine[*column] {
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::String(text) => text.clone(),
+            other => other.to_string(),
+        }))?;
+    }
+    writer.into_inner().map_err(|err| err.into_error().into())
+}
+
+async fn export_wines(
+    Query(params): Query<ExportQuery>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+    axum::extract::State(reviews): axum::extract::State<ReviewLoader>,
+) -> Result<Response, StatusCode> {
+    let wines = sqlx::query_as::<_, Wine>(&format!("SELECT {} FROM wine_ratings WHERE tenant_id = ? ORDER BY id", WINE_COLUMNS))
+        .bind(&tenant.0)
+        .fetch_all(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let stripped: &[String] = if params.anonymize.unwrap_or(false) { &config.anonymized_fields } else { &[] };
+    let format = params.format.unwrap_or(FileFormat::Json);
+
+    let (content_type, filename, body) = match format {
+        FileFormat::Json => {
+            let wines = reviews.attach(wines).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let mut value = serde_json::to_value(&wines).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            strip_fields(&mut value, stripped);
+            let body = serde_json::to_vec_pretty(&value).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            ("application/json", "wines.json", body)
+        }
+        FileFormat::Csv => {
+            let rows: Vec<serde_json::Value> = wines
+                .iter()
+                .map(serde_json::to_value)
+                .collect::<Result<_, _>>()
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let body = wines_to_csv(&rows, stripped).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            ("text/csv", "wines.csv", body)
+        }
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+fn current_year() -> i64 {
+    // Close enough for wine ages; only off by a few hours around New Year.
+    let elapsed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    1970 + (elapsed.as_secs() / SECONDS_PER_YEAR) as i64
+}
+
+// Bell curve around the variety's peak age, as wide as the peak itself, so a red
+// peaking at 8 years keeps ~60% of its rating at release and again at 16 years.
+fn drinkability_score(rating: f64, age: i64, peak_age: f64) -> f64 {
+    let distance = (age as f64 - peak_age) / peak_age;
+    rating * (-0.5 * distance * distance).exp()
+}
+
+async fn get_wine_drinkability(
+    tenant: Tenant,
+    Path(id): Path<i64>,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> Result<Response, StatusCode> {
+    let wine = fetch_wine(&pool, &tenant, id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let (Some(rating), Some(vintage)) = (wine.rating, wine.vintage) else {
+        let error = ErrorMessage {
+            error: "drinkability needs both a rating and a vintage".to_string(),
+        };
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(error)).into_response());
+    };
+
+    let age = (current_year() - vintage).max(0);
+    let peak_age = config.peak_age(wine.variety.as_deref());
+    let score = round_rating(drinkability_score(rating, age, peak_age), config.rating_decimals);
+
+    Ok(Json(Drinkability {
+        id: wine.id,
+        score,
+        rating,
+        vintage,
+        age,
+        variety: wine.variety,
+        peak_age,
+    })
+    .into_response())
+}
+
+async fn wine_exists(
+    tenant: Tenant,
+    Path(id): Path<i64>,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> StatusCode {
+    let exists = sqlx::query_scalar::<_, i64>("SELECT 1 FROM wine_ratings WHERE tenant_id = ? AND id = ?")
+        .bind(&tenant.0)
+        .bind(id)
+        .fetch_optional(&pool)
+        .await;
+
+    match exists {
+        Ok(Some(_)) => StatusCode::OK,
+        Ok(None) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+async fn create_wine(
+    tenant: Tenant,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Json(new_wine): Json<NewWine>,
+) -> Result<Response, StatusCode> {
+    let errors = new_wine.validation_errors();
+    if !errors.is_empty() {
+        return Ok(validation_failed(errors));
+    }
+
+    let region = normalize_region(&state.pool, new_wine.region.as_deref())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let name = new_wine.name.trim();
+    let query = format!(
+        "INSERT INTO wine_ratings
+            (tenant_id, name, region, variety, rating, notes, vintage, image_url, latitude, longitude, price, currency, source,
+            name_search, notes_search)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING {}",
+        WINE_COLUMNS
+    );
+    let inserted = retry_transient(&state.config, || {
+        sqlx::query_as::<_, Wine>(&query)
+            .bind(&tenant.0)
+            .bind(name)
+            .bind(&region)
+            .bind(&new_wine.variety)
+            .bind(new_wine.rating)
+            .bind(&new_wine.notes)
+            .bind(new_wine.vintage)
+            .bind(&new_wine.image_url)
+            .bind(new_wine.latitude)
+            .bind(new_wine.longitude)
+            .bind(new_wine.price)
+            .bind(&new_wine.currency)
+            .bind(&new_wine.source)
+            .bind(fold_search_text(name))
+            .bind(new_wine.notes.as_deref().map(fold_search_text))
+            .fetch_one(&state.pool)
+    })
+    .await;
+    let wine = match inserted {
+        Ok(wine) => wine,
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            return Ok(name_conflict(name, new_wine.vintage));
+        }
+        Err(err) => return Err(database_error(err)),
+    };
+
+    if let Some(webhook) = &state.webhook {
+        webhook.notify(&wine);
+    }
+    let _ = state.events.send((tenant.clone(), wine.clone()));
+
+    let location = format!("/wines/{}", wine.id);
+    Ok((StatusCode::CREATED, [(header::LOCATION, location)], Json(wine)).into_response())
+}
+
+fn version_etag(version: i64) -> String {
+    format!("\"{}\"", version)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum IfMatch {
+    // If-Match: * matches whatever version currently exists.
+    Any,
+    Version(i64),
+}
+
+// Accepts the ETag form ("3", optionally weak) as well as a bare version number.
+fn if_match_version(headers: &HeaderMap) -> Result<Option<IfMatch>, StatusCode> {
+    let Some(value) = headers.get(header::IF_MATCH) else {
+        return Ok(None);
+    };
+    let value = value.to_str().map_err(|_| StatusCode::BAD_REQUEST)?.trim();
+    if value == "*" {
+        return Ok(Some(IfMatch::Any));
+    }
+    value
+        .trim_start_matches("W/")
+        .trim_matches('"')
+        .parse()
+        .map(|version| Some(IfMatch::Version(version)))
+        .map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+enum VersionCheck {
+    Required,
+    Stale(Option<i64>),
+}
+
+impl IntoResponse for VersionCheck {
+    fn into_response(self) -> Response {
+        match self {
+            VersionCheck::Required => json_error(
+                StatusCode::PRECONDITION_REQUIRED,
+                "If-Match with the wine's current version is required to update it",
+            ),
+            VersionCheck::Stale(current_version) => {
+                let conflict = VersionConflict {
+                    error: "the wine was changed since that version".to_string(),
+                    current_version,
+                };
+                (StatusCode::CONFLICT, Json(conflict)).into_response()
+            }
+        }
+    }
+}
+
+// Updating an existing wine needs its current version; creating one must not claim a version.
+fn check_version(current: Option<i64>, expected: Option<IfMatch>) -> Result<(), VersionCheck> {
+    match (current, expected) {
+        (Some(_), None) => Err(VersionCheck::Required),
+        (None, Some(_)) => Err(VersionCheck::Stale(None)),
+        (Some(current), Some(IfMatch::Version(expected))) if current != expected => {
+            Err(VersionCheck::Stale(Some(current)))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn name_conflict(name: &str, vintage: Option<i64>) -> Response {
+    let conflict = ConflictError {
+        error: "a wine with this name and vintage already exists".to_string(),
+        name: name.to_string(),
+        vintage,
+    };
+    (StatusCode::CONFLICT, Json(conflict)).into_response()
+}
+
+async fn upsert_wine_by_external_id(
+    Path(external_id): Path<String>,
+    tenant: Tenant,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    headers: HeaderMap,
+    Json(new_wine): Json<NewWine>,
+) -> Result<Response, StatusCode> {
+    let expected_version = if_match_version(&headers)?;
+    let errors = new_wine.validation_errors();
+    if !errors.is_empty() {
+        return Ok(validation_failed(errors));
+    }
+
+    let name = new_wine.name.trim();
+    let query = format!(
+        "INSERT INTO wine_ratings
+            (tenant_id, external_id, name, region, variety, rating, notes, vintage, image_url, latitude, longitude, price, currency, source,
+            name_search, notes_search)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (tenant_id, external_id) DO UPDATE SET
+            name = excluded.name,
+            region = excluded.region,
+            variety = excluded.variety,
+            rating = excluded.rating,
+            notes = excluded.notes,
+            vintage = excluded.vintage,
+            image_url = excluded.image_url,
+            latitude = excluded.latitude,
+            longitude = excluded.longitude,
+            price = excluded.price,
+            currency = excluded.currency,
+            source = excluded.source,
+            name_search = excluded.name_search,
+            notes_search = excluded.notes_search,
+            version = wine_ratings.version + 1
+        RETURNING {}",
+        WINE_COLUMNS
+    );
+    let upserted = retry_transient(&state.config, || async {
+        let mut tx = state.pool.begin().await?;
+        let region = normalize_region(&mut *tx, new_wine.region.as_deref()).await?;
+        let existing = sqlx::query_scalar::<_, i64>(
+            "SELECT version FROM wine_ratings WHERE tenant_id = ? AND external_id = ?",
+        )
+        .bind(&tenant.0)
+        .bind(&external_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        // Importers re-run upserts blindly, so the version is only checked when If-Match is sent.
+        if expected_version.is_some()
+            && let Err(check) = check_version(existing, expected_version)
+        {
+            return Ok(Err(check));
+        }
+        let wine = sqlx::query_as::<_, Wine>(&query)
+            .bind(&tenant.0)
+            .bind(&external_id)
+            .bind(name)
+            .bind(&region)
+            .bind(&new_wine.variety)
+            .bind(new_wine.rating)
+            .bind(&new_wine.notes)
+            .bind(new_wine.vintage)
+            .bind(&new_wine.image_url)
+            .bind(new_wine.latitude)
+            .bind(new_wine.longitude)
+            .bind(new_wine.price)
+            .bind(&new_wine.currency)
+            .bind(&new_wine.source)
+            .bind(fold_search_text(name))
+            .bind(new_wine.notes.as_deref().map(fold_search_text))
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Ok((existing, wine)))
+    })
+    .await;
+    let (existing, wine) = match upserted {
+        Ok(Ok(upserted)) => upserted,
+        Ok(Err(check)) => return Ok(check.into_response()),
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            return Ok(name_conflict(name, new_wine.vintage));
+        }
+        Err(err) => return Err(database_error(err)),
+    };
+
+    let created = existing.is_none();
+    if created {
+        if let Some(webhook) = &state.webhook {
+            webhook.notify(&wine);
+        }
+        let _ = state.events.send((tenant.clone(), wine.clone()));
+    }
+
+    let status = if created { StatusCode::CREATED } else { StatusCode::OK };
+    let headers = [(header::LOCATION, format!("/wines/{}", wine.id)), (header::ETAG, version_etag(wine.version))];
+    Ok((status, headers, Json(UpsertResult { created, wine })).into_response())
+}
+
+fn changed_fields(before: &Wine, after: &Wine) -> Vec<String> {
+    let (Ok(before), Ok(after)) = (serde_json::to_value(before), serde_json::to_value(after)) else {
+        return Vec::new();
+    };
+    WINE_FIELDS
+        .iter()
+        .map(|(name, _, _)| *name)
+        // Maintained by the server on every write rather than changed by the patch.
+        .filter(|name| !matches!(*name, "rating_normalized" | "version"))
+        .filter(|name| before.get(name) != after.get(name))
+        .map(str::to_string)
+        .collect()
+}
+
+enum PatchOutcome {
+    Missing,
+    Invalid(Vec<FieldError>),
+    Conflict { name: String, vintage: Option<i64> },
+    Version(VersionCheck),
+    Patched(Box<(Wine, Wine)>),
+}
+
+async fn patch_wine(
+    tenant: Tenant,
+    Path(id): Path<i64>,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+    headers: HeaderMap,
+    Json(patch): Json<WinePatch>,
+) -> Result<Response, StatusCode> {
+    let expected_version = if_match_version(&headers)?;
+    let outcome = retry_transient(&config, || async {
+        let mut tx = pool.begin().await?;
+        let before = sqlx::query_as::<_, Wine>(&format!(
+            "SELECT {} FROM wine_ratings WHERE tenant_id = ? AND id = ?",
+            WINE_COLUMNS
+        ))
+        .bind(&tenant.0)
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(before) = before else {
+            return Ok(PatchOutcome::Missing);
+        };
+        if let Err(check) = check_version(Some(before.version), expected_version) {
+            return Ok(PatchOutcome::Version(check));
+        }
+
+        let patched = patch.clone().apply(&before);
+        let errors = patched.validation_errors();
+        if !errors.is_empty() {
+            return Ok(PatchOutcome::Invalid(errors));
+        }
+        let region = if patch.region.is_some() {
+            normalize_region(&mut *tx, patched.region.as_deref()).await?
+        } else {
+            patched.region.clone()
+        };
+
+        let wine = match update_wine(&mut tx, &tenant, id, &patched, region).await {
+            Ok(wine) => wine,
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                return Ok(PatchOutcome::Conflict {
+                    name: patched.name.trim().to_string(),
+                    vintage: patched.vintage,
+                });
+            }
+            Err(err) => return Err(err),
+        };
+        tx.commit().await?;
+        Ok(PatchOutcome::Patched(Box::new((before, wine))))
+    })
+    .await
+    .map_err(database_error)?;
+
+    match outcome {
+        PatchOutcome::Missing => Err(StatusCode::NOT_FOUND),
+        PatchOutcome::Invalid(errors) => Ok(validation_failed(errors)),
+        PatchOutcome::Conflict { name, vintage } => Ok(name_conflict(&name, vintage)),
+        PatchOutcome::Version(check) => Ok(check.into_response()),
+        PatchOutcome::Patched(patched) => {
+            let (before, wine) = *patched;
+            let changed = changed_fields(&before, &wine);
+            let etag = version_etag(wine.version);
+            Ok(([(header::ETAG, etag)], Json(PatchResult { wine, changed })).into_response())
+        }
+    }
+}
+
+async fn update_wine(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    tenant: &Tenant,
+    id: i64,
+    wine: &NewWine,
+    region: Option<String>,
+) -> Result<Wine, sqlx::Error> {
+    sqlx::query_as::<_, Wine>(&format!(
+        "UPDATE wine_ratings SET
+            name = ?, region = ?, variety = ?, rating = ?, notes = ?,
+            vintage = ?, image_url = ?, latitude = ?, longitude = ?, price = ?, currency = ?, source = ?,
+            name_search = ?, notes_search = ?, version = version + 1
+        WHERE tenant_id = ? AND id = ?
+        RETURNING {}",
+        WINE_COLUMNS
+    ))
+    .bind(wine.name.trim())
+    .bind(region)
+    .bind(&wine.variety)
+    .bind(wine.rating)
+    .bind(&wine.notes)
+    .bind(wine.vintage)
+    .bind(&wine.image_url)
+    .bind(wine.latitude)
+    .bind(wine.longitude)
+    .bind(wine.price)
+    .bind(&wine.currency)
+    .bind(&wine.source)
+    .bind(fold_search_text(wine.name.trim()))
+    .bind(wine.notes.as_deref().map(fold_search_text))
+    .bind(&tenant.0)
+    .bind(id)
+    .fetch_one(&mut **tx)
+    .await
+}
+
+enum BulkPatchOutcome {
+    Invalid(Vec<FieldError>),
+    Conflict { name: String, vintage: Option<i64> },
+    Updated(usize),
+}
+
+async fn bulk_patch_wines(
+    Query(filters): Query<WineFilters>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+    Json(patch): Json<WinePatch>,
+) -> Result<Response, StatusCode> {
+    if !filters.has_conditions() {
+        return Ok(json_error(StatusCode::BAD_REQUEST, "bulk updates require at least one filter"));
+    }
+    if patch.name.is_some() {
+        return Ok(validation_failed(vec![FieldError::new("name", "cannot be set on many wines at once")]));
+    }
+
+    let outcome = retry_transient(&config, || async {
+        let mut tx = pool.begin().await?;
+        let region = match &patch.region {
+            Some(region) => normalize_region(&mut *tx, Some(region)).await?,
+            None => None,
+        };
+        let mut query = QueryBuilder::new(format!("SELECT {} FROM wine_ratings", WINE_COLUMNS));
+        push_filter_conditions(&mut query, &tenant, &filters);
+        let wines = query.build_query_as::<Wine>().fetch_all(&mut *tx).await?;
+
+        for wine in &wines {
+            let patched = patch.clone().apply(wine);
+            let errors = patched.validation_errors();
+            if !errors.is_empty() {
+                return Ok(BulkPatchOutcome::Invalid(errors));
+            }
+            let region = if patch.region.is_some() { region.clone() } else { patched.region.clone() };
+            match update_wine(&mut tx, &tenant, wine.id, &patched, region).await {
+                Ok(_) => {}
+                Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                    return Ok(BulkPatchOutcome::Conflict {
+                        name: patched.name.trim().to_string(),
+                        vintage: patched.vintage,
+                    });
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        tx.commit().await?;
+        Ok(BulkPatchOutcome::Updated(wines.len()))
+    })
+    .await
+    .map_err(database_error)?;
+
+    match outcome {
+        BulkPatchOutcome::Invalid(errors) => Ok(validation_failed(errors)),
+        BulkPatchOutcome::Conflict { name, vintage } => Ok(name_conflict(&name, vintage)),
+        BulkPatchOutcome::Updated(updated) => Ok(Json(BulkUpdate { updated }).into_response()),
+    }
+}
+
+async fn wine_events(
+    tenant: Tenant,
+    axum::extract::State(events): axum::extract::State<broadcast::Sender<(Tenant, Wine)>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = BroadcastStream::new(events.subscribe()).filter_map(move |message| match message {
+        Ok((owner, _)) if owner != tenant => None,
+        Ok((_, wine)) => Event::default().event("wine_created").json_data(&wine).ok().map(Ok),
+        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+            tracing::warn!("wine event subscriber lagged, skipped {} events", skipped);
+            None
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn get_wine_schema() -> Json<Vec<FieldSchema>> {
+    let fields = WINE_FIELDS
+        .iter()
+        .map(|(name, field_type, nullable)| FieldSchema {
+            name: name.to_string(),
+            field_type: field_type.to_string(),
+            nullable: *nullable,
+            filterable: FILTERABLE_COLUMNS.contains(name),
+            sortable: SORTABLE_COLUMNS.contains(name),
+        })
+        .collect();
+
+    Json(fields)
+}
+
+async fn get_duplicate_wines(
+    Query(params): Query<DuplicateQuery>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<Vec<DuplicateGroup>>, StatusCode> {
+    let by_region = params.by_region.unwrap_or(false);
+    let query = if by_region {
+        format!(
+            "SELECT {} FROM wine_ratings
+            JOIN (SELECT LOWER(TRIM(name)) AS dup_name, region AS dup_region FROM wine_ratings WHERE tenant_id = ?1
+                GROUP BY LOWER(TRIM(name)), region HAVING COUNT(*) > 1) d
+            ON LOWER(TRIM(wine_ratings.name)) = d.dup_name AND wine_ratings.region IS d.dup_region
+            WHERE wine_ratings.tenant_id = ?1
+            ORDER BY LOWER(TRIM(wine_ratings.name)), wine_ratings.region, wine_ratings.id",
+            WINE_COLUMNS
+        )
+    } else {
+        format!(
+            "SELECT {} FROM wine_ratings
+            JOIN (SELECT LOWER(TRIM(name)) AS dup_name FROM wine_ratings WHERE tenant_id = ?1
+                GROUP BY LOWER(TRIM(name)) HAVING COUNT(*) > 1) d
+            ON LOWER(TRIM(wine_ratings.name)) = d.dup_name
+            WHERE wine_ratings.tenant_id = ?1
+            ORDER BY LOWER(TRIM(wine_ratings.name)), wine_ratings.id",
+            WINE_COLUMNS
+        )
+    };
+
+    let wines = sqlx::query_as::<_, Wine>(&query)
+        .bind(&tenant.0)
+        .fetch_all(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut groups: Vec<DuplicateGroup> = Vec::new();
+    for wine in wines {
+        let name = wine.name.trim().to_lowercase();
+        let region = if by_region { wine.region.clone() } else { None };
+        match groups.last_mut() {
+            Some(group) if group.name == name && group.region == region => group.wines.push(wine),
+            _ => groups.push(DuplicateGroup {
+                name,
+                region,
+                wines: vec![wine],
+            }),
+        }
+    }
+
+    Ok(Json(groups))
+}
+
+async fn get_name_collisions(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<Vec<NameCollision>>, StatusCode> {
+    let rows = sqlx::query_as::<_, (String, String)>(
+        "SELECT DISTINCT name, region FROM wine_ratings
+        WHERE tenant_id = ?1 AND region IS NOT NULL AND name IN (
+            SELECT name FROM wine_ratings WHERE tenant_id = ?1
+            GROUP BY name HAVING COUNT(DISTINCT region) > 1
+        )
+        ORDER BY name, region",
+    )
+    .bind(&tenant.0)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut collisions: Vec<NameCollision> = Vec::new();
+    for (name, region) in rows {
+        match collisions.last_mut() {
+            Some(collision) if collision.name == name => collision.regions.push(region),
+            _ => collisions.push(NameCollision { name, regions: vec![region] }),
+        }
+    }
+
+    Ok(Json(collisions))
+}
+
+fn pick_weighted_by_rating<R: Rng>(wines: &[Wine], rng: &mut R) -> Option<usize> {
+    let weights = wines.iter().map(|wine| wine.rating.unwrap_or(0.0).max(0.0));
+    let distribution = WeightedIndex::new(weights).ok()?;
+    Some(distribution.sample(rng))
+}
+
+async fn get_weighted_random_wine(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<Wine>, StatusCode> {
+    let mut wines = sqlx::query_as::<_, Wine>(&format!(
+        "SELECT {} FROM wine_ratings WHERE tenant_id = ? AND rating IS NOT NULL AND rating > 0",
+        WINE_COLUMNS
+    ))
+    .bind(&tenant.0)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let index = pick_weighted_by_rating(&wines, &mut rand::thread_rng()).ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(wines.swap_remove(index)))
+}
+
+async fn update_ratings(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+    Json(updates): Json<Vec<RatingUpdate>>,
+) -> Result<Json<RatingUpdateSummary>, StatusCode> {
+    if updates.iter().any(|update| !is_valid_rating(update.rating)) {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let summary = retry_transient(&config, || async {
+        let mut tx = pool.begin().await?;
+        let mut summary = RatingUpdateSummary { updated: 0, not_found: 0 };
+
+        for update in &updates {
+            let result = sqlx::query("UPDATE wine_ratings SET rating = ?, version = version + 1 WHERE tenant_id = ? AND id = ?")
+                .bind(update.rating)
+                .bind(&tenant.0)
+                .bind(update.id)
+                .execute(&mut *tx)
+                .await?;
+            if result.rows_affected() > 0 {
+                summary.updated += 1;
+            } else {
+                summary.not_found += 1;
+            }
+        }
+
+        tx.commit().await?;
+        Ok(summary)
+    })
+    .await
+    .map_err(database_error)?;
+
+    Ok(Json(summary))
+}
+
+fn parse_import(format: FileFormat, body: &[u8]) -> Result<Vec<NewWine>, String> {
+    match format {
+        FileFormat::Json => serde_json::from_slice(body).map_err(|err| format!("invalid JSON: {}", err)),
+        FileFormat::Csv => csv::Reader::from_reader(body)
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .map_err(|err| format!("invalid CSV: {}", err)),
+    }
+}
+
+fn validate_csv_rows(body: &[u8]) -> ImportValidation {
+    let mut reader = csv::Reader::from_reader(body);
+    let headers = reader.headers().cloned().unwrap_or_default();
+    let rows: Vec<ImportRowReport> = reader
+        .deserialize::<NewWine>()
+        .enumerate()
+        .map(|(index, record)| {
+            let errors = match record {
+                Ok(wine) => wine.validation_errors(),
+                Err(err) => {
+                    // Point at the offending column when the parser knows which one it was.
+                    let field = match err.kind() {
+                        csv::ErrorKind::Deserialize { err, .. } => {
+                            err.field().and_then(|field| headers.get(field as usize))
+                        }
+                        _ => None,
+                    };
+                    vec![FieldError::new(field.unwrap_or("row"), &err.to_string())]
+                }
+            };
+            ImportRowReport {
+                row: index + 1,
+                valid: errors.is_empty(),
+                errors,
+            }
+        })
+        .collect();
+
+    let valid = rows.iter().filter(|row| row.valid).count();
+    ImportValidation {
+        summary: ImportValidationSummary {
+            total: rows.len(),
+            valid,
+            invalid: rows.len() - valid,
+        },
+        rows,
+    }
+}
+
+async fn validate_import(body: axum::body::Bytes) -> Json<ImportValidation> {
+    Json(validate_csv_rows(&body))
+}
+
+// Validates every row before writing any, so a bad file imports nothing; rows whose
+// name and vintage already exist are counted as duplicates rather than failing the batch.
+async fn import_wines(
+    pool: &SqlitePool,
+    config: &Config,
+    tenant: &Tenant,
+    wines: &[NewWine],
+) -> Result<Response, StatusCode> {
+    let errors: Vec<FieldError> = wines
+        .iter()
+        .enumerate()
+        .flat_map(|(index, wine)| {
+            wine.validation_errors()
+                .into_iter()
+                .map(move |error| FieldError::new(&format!("[{}].{}", index, error.field), &error.message))
+        })
+        .collect();
+    if !errors.is_empty() {
+        return Ok(validation_failed(errors));
+    }
+
+    let summary = retry_transient(config, || async {
+        let mut tx = pool.begin().await?;
+        let mut summary = ImportSummary { imported: 0, duplicates: 0 };
+
+        for wine in wines {
+            let region = normalize_region(&mut *tx, wine.region.as_deref()).await?;
+            let inserted = sqlx::query(
+                "INSERT INTO wine_ratings
+                    (tenant_id, name, region, variety, rating, notes, vintage, image_url, latitude, longitude, price, currency, source,
+                    name_search, notes_search)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(&tenant.0)
+            .bind(wine.name.trim())
+            .bind(&region)
+            .bind(&wine.variety)
+            .bind(wine.rating)
+            .bind(&wine.notes)
+            .bind(wine.vintage)
+            .bind(&wine.image_url)
+            .bind(wine.latitude)
+            .bind(wine.longitude)
+            .bind(wine.price)
+            .bind(&wine.currency)
+            .bind(&wine.source)
+            .bind(fold_search_text(wine.name.trim()))
+            .bind(wine.notes.as_deref().map(fold_search_text))
+            .execute(&mut *tx)
+            .await;
+            match inserted {
+                Ok(_) => summary.imported += 1,
+                Err(sqlx::Error::Database(err)) if err.is_unique_violation() => summary.duplicates += 1,
+                Err(err) => return Err(err),
+            }
+        }
+
+        tx.commit().await?;
+        Ok(summary)
+    })
+    .await
+    .map_err(database_error)?;
+
+    Ok(Json(summary).into_response())
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            // 100.64.0.0/10 is carrier-grade NAT space, as internal as the RFC 1918 ranges.
+            let shared = first == 100 && (second & 0xc0) == 64;
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast() || shared)
+        }
+        IpAddr::V6(ip) => {
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_unique_local() || ip.is_unicast_link_local())
+        }
+    }
+}
+
+// Resolves the host once and hands back the address to connect to, so a DNS answer that
+// changes between the check and the request cannot smuggle the fetch onto an internal host.
+async fn resolve_import_host(url: &reqwest::Url, allow_private: bool) -> Result<(String, SocketAddr), Response> {
+    let invalid_url = |message: &str| validation_failed(vec![FieldError::new("url", message)]);
+    let host = url.host_str().ok_or_else(|| invalid_url("must name a host"))?;
+    let port = url.port_or_known_default().ok_or_else(|| invalid_url("must name a port"))?;
+
+    let addresses: Vec<SocketAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|err| {
+                let error = ErrorMessage {
+                    error: format!("could not resolve {}: {}", host, err),
+                };
+                (StatusCode::BAD_GATEWAY, Json(error)).into_response()
+            })?
+            .collect(),
+    };
+    if !allow_private && addresses.iter().any(|address| !is_public_ip(address.ip())) {
+        return Err(invalid_url("must not point at a loopback, private or link-local address"));
+    }
+    let address = addresses.first().copied().ok_or_else(|| invalid_url("host has no addresses"))?;
+
+    Ok((host.to_string(), address))
+}
+
+async fn fetch_import_body(url: &str, config: &Config) -> Result<Vec<u8>, Response> {
+    let upstream_error = |err: reqwest::Error| {
+        let error = ErrorMessage {
+            error: format!("could not fetch {}: {}", url, err),
+        };
+        (StatusCode::BAD_GATEWAY, Json(error)).into_response()
+    };
+    let too_large = || {
+        let error = ErrorMessage {
+            error: format!("import files are limited to {} bytes", MAX_IMPORT_BYTES),
+        };
+        (StatusCode::PAYLOAD_TOO_LARGE, Json(error)).into_response()
+    };
+
+    let parsed = reqwest::Url::parse(url)
+        .map_err(|_| validation_failed(vec![FieldError::new("url", "must be an absolute http or https URL")]))?;
+    let (host, address) = resolve_import_host(&parsed, config.import_allow_private_hosts).await?;
+    // Redirects are never followed: each hop would need the same address check, and a public
+    // URL bouncing to an internal one is exactly what the check exists to stop.
+    let client = reqwest::Client::builder()
+        .timeout(IMPORT_FETCH_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(&host, address)
+        .build()
+        .map_err(upstream_error)?;
+    let mut response = client
+        .get(parsed)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(upstream_error)?;
+    if response.status().is_redirection() {
+        let error = ErrorMessage {
+            error: format!("could not fetch {}: redirects are not followed", url),
+        };
+        return Err((StatusCode::BAD_GATEWAY, Json(error)).into_response());
+    }
+    if response.content_length().is_some_and(|length| length > MAX_IMPORT_BYTES as u64) {
+        return Err(too_large());
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(upstream_error)? {
+        if body.len() + chunk.len() > MAX_IMPORT_BYTES {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+async fn import_wines_from_url(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+    Json(import): Json<UrlImport>,
+) -> Result<Response, StatusCode> {
+    if !is_http_url(&import.url) {
+        return Ok(validation_failed(vec![FieldError::new("url", "must be an absolute http or https URL")]));
+    }
+
+    let body = match fetch_import_body(&import.url, &config).await {
+        Ok(body) => body,
+        Err(response) => return Ok(response),
+    };
+    let wines = match parse_import(import.format, &body) {
+        Ok(wines) => wines,
+        Err(error) => return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(ErrorMessage { error })).into_response()),
+    };
+
+    import_wines(&pool, &config, &tenant, &wines).await
+}
+
+async fn backfill_normalized_regions(
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<RegionBackfillSummary>, StatusCode> {
+    let mut summary = RegionBackfillSummary { updated: 0 };
+    let mut last_id = 0;
+
+    loop {
+        let mut tx = pool.begin().await.map_err(database_error)?;
+        let rows = sqlx::query_as::<_, (i64, String)>(
+            "SELECT id, region FROM wine_ratings WHERE id > ? AND region IS NOT NULL ORDER BY id LIMIT ?",
+        )
+        .bind(last_id)
+        .bind(NORMALIZE_BATCH_SIZE)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(database_error)?;
+        let Some(&(batch_last_id, _)) = rows.last() else {
+            break;
+        };
+
+        for (id, region) in &rows {
+            let normalized = normalize_region(&mut *tx, Some(region))
+                .await
+                .map_err(database_error)?;
+            if normalized.as_deref() == Some(region.as_str()) {
+                continue;
+            }
+            sqlx::query("UPDATE wine_ratings SET region = ?, version = version + 1 WHERE id = ?")
+                .bind(normalized)
+                .bind(id)
+                .execute(&mut *tx)
+                .await
+                .map_err(database_error)?;
+            summary.updated += 1;
+        }
+
+        tx.commit().await.map_err(database_error)?;
+        last_id = batch_last_id;
+    }
+
+    Ok(Json(summary))
+}
+
+async fn list_region_aliases(
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<Vec<RegionAlias>>, StatusCode> {
+    let aliases = sqlx::query_as::<_, RegionAlias>("SELECT alias, canonical FROM region_aliases ORDER BY alias")
+        .fetch_all(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(aliases))
+}
+
+async fn create_region_alias(
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    Json(alias): Json<RegionAlias>,
+) -> Result<(StatusCode, Json<RegionAlias>), StatusCode> {
+    let alias = RegionAlias {
+        alias: alias.alias.trim().to_string(),
+        canonical: alias.canonical.trim().to_string(),
+    };
+    if alias.alias.is_empty() || alias.canonical.is_empty() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    sqlx::query(
+        "INSERT INTO region_aliases (alias, canonical) VALUES (?, ?)
+        ON CONFLICT(alias) DO UPDATE SET canonical = excluded.canonical",
+    )
+    .bind(&alias.alias)
+    .bind(&alias.canonical)
+    .execute(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok((StatusCode::CREATED, Json(alias)))
+}
+
+async fn delete_region_alias(
+    Path(alias): Path<String>,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<StatusCode, StatusCode> {
+    let result = sqlx::query("DELETE FROM region_aliases WHERE alias = ?")
+        .bind(alias)
+        .execute(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Two-sided 95% critical values of Student's t distribution for 1..=30 degrees of freedom.
+const T_CRITICAL_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160, 2.145, 2.131,
+    2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056, 2.052, 2.048, 2.045, 2.042,
+];
+const Z_CRITICAL_95: f64 = 1.96;
+
+fn confidence_interval(variety: Option<String>, ratings: &[f64]) -> ConfidenceInterval {
+    let count = ratings.len();
+    let mut interval = ConfidenceInterval {
+        variety,
+        count,
+        mean: None,
+        std_dev: None,
+        lower: None,
+        upper: None,
+    };
+    if count == 0 {
+        return interval;
+    }
+
+    let mean = ratings.iter().sum::<f64>() / count as f64;
+    interval.mean = Some(mean);
+    if count < 2 {
+        return interval;
+    }
+
+    let variance = ratings.iter().map(|rating| (rating - mean).powi(2)).sum::<f64>() / (count - 1) as f64;
+    let std_dev = variance.sqrt();
+    let critical = T_CRITICAL_95.get(count - 2).copied().unwrap_or(Z_CRITICAL_95);
+    let margin = critical * std_dev / (count as f64).sqrt();
+
+    interval.std_dev = Some(std_dev);
+    interval.lower = Some(mean - margin);
+    interval.upper = Some(mean + margin);
+    interval
+}
+
+async fn get_rating_confidence(
+    Query(params): Query<ConfidenceQuery>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<ConfidenceInterval>, StatusCode> {
+    let ratings = sqlx::query_scalar::<_, f64>(
+        "SELECT rating FROM wine_ratings WHERE tenant_id = ? AND rating IS NOT NULL AND (? IS NULL OR variety = ?)",
+    )
+    .bind(&tenant.0)
+    .bind(&params.variety)
+    .bind(&params.variety)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(confidence_interval(params.variety, &ratings)))
+}
+
+fn pearson_correlation(pairs: &[(f64, f64)]) -> Option<f64> {
+    if pairs.len() < 2 {
+        return None;
+    }
+
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = pairs.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance = pairs.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum::<f64>();
+    let spread_x = pairs.iter().map(|(x, _)| (x - mean_x).powi(2)).sum::<f64>().sqrt();
+    let spread_y = pairs.iter().map(|(_, y)| (y - mean_y).powi(2)).sum::<f64>().sqrt();
+    if spread_x == 0.0 || spread_y == 0.0 {
+        return None;
+    }
+    Some(covariance / (spread_x * spread_y))
+}
+
+async fn get_price_rating_correlation(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<PriceRatingCorrelation>, StatusCode> {
+    let pairs = sqlx::query_as::<_, (f64, f64)>(
+        "SELECT price, rating FROM wine_ratings WHERE tenant_id = ? AND price IS NOT NULL AND rating IS NOT NULL",
+    )
+    .bind(&tenant.0)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(PriceRatingCorrelation {
+        coefficient: pearson_correlation(&pairs),
+        count: pairs.len(),
+    }))
+}
+
+const DEFAULT_OUTLIER_SIGMA: f64 = 2.0;
+// Below this many rated wines a variety's spread says too little to call any rating an outlier.
+const MIN_VARIETY_OUTLIER_SAMPLE: usize = 3;
+
+fn rating_spread(ratings: &[f64]) -> Option<(f64, f64)> {
+    if ratings.len() < 2 {
+        return None;
+    }
+    let mean = ratings.iter().sum::<f64>() / ratings.len() as f64;
+    let std_dev = (ratings.iter().map(|rating| (rating - mean).powi(2)).sum::<f64>() / ratings.len() as f64).sqrt();
+    Some((mean, std_dev))
+}
+
+fn rating_outliers(wines: Vec<Wine>, sigma: f64) -> Vec<RatingOutlier> {
+    let ratings: Vec<f64> = wines.iter().filter_map(|wine| wine.rating).collect();
+    let Some((mean, std_dev)) = rating_spread(&ratings) else {
+        return Vec::new();
+    };
+    if std_dev == 0.0 {
+        return Vec::new();
+    }
+
+    wines
+        .into_iter()
+        .filter_map(|wine| {
+            let z_score = (wine.rating? - mean) / std_dev;
+            (z_score.abs() > sigma).then_some(RatingOutlier { wine, z_score })
+        })
+        .collect()
+}
+
+async fn get_rating_outliers(
+    Query(params): Query<OutlierQuery>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<Vec<RatingOutlier>>, StatusCode> {
+    let sigma = params.sigma.unwrap_or(DEFAULT_OUTLIER_SIGMA);
+    if !sigma.is_finite() || sigma <= 0.0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let wines = sqlx::query_as::<_, Wine>(&format!(
+        "SELECT {} FROM wine_ratings WHERE tenant_id = ? AND rating IS NOT NULL ORDER BY id",
+        WINE_COLUMNS
+    ))
+    .bind(&tenant.0)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(rating_outliers(wines, sigma)))
+}
+
+async fn get_variety_outliers(
+    Path(variety): Path<String>,
+    Query(params): Query<OutlierQuery>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> Result<Json<VarietyOutliers>, StatusCode> {
+    let sigma = params.sigma.unwrap_or(DEFAULT_OUTLIER_SIGMA);
+    if !sigma.is_finite() || sigma <= 0.0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let wines = sqlx::query_as::<_, Wine>(&format!(
+        "SELECT {} FROM wine_ratings WHERE tenant_id = ? AND variety = ? AND rating IS NOT NULL ORDER BY id",
+        WINE_COLUMNS
+    ))
+    .bind(&tenant.0)
+    .bind(&variety)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if wines.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let ratings: Vec<f64> = wines.iter().filter_map(|wine| wine.rating).collect();
+    let (mean, std_dev) = match rating_spread(&ratings) {
+        Some((mean, std_dev)) => (mean, Some(round_rating(std_dev, config.rating_decimals))),
+        None => (ratings[0], None),
+    };
+    let count = wines.len();
+    let outliers = if count >= MIN_VARIETY_OUTLIER_SAMPLE { rating_outliers(wines, sigma) } else { Vec::new() };
+
+    Ok(Json(VarietyOutliers {
+        variety,
+        count,
+        mean: round_rating(mean, config.rating_decimals),
+        std_dev,
+        outliers,
+    }))
+}
+
+const KEY_METADATA_FIELDS: &[&str] = &["region", "variety", "rating"];
+
+async fn get_incomplete_wines(
+    Query(params): Query<IncompleteQuery>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<Vec<IncompleteWine>>, StatusCode> {
+    let fields: Vec<&str> = match params.field.as_deref() {
+        Some(field) if KEY_METADATA_FIELDS.contains(&field) => vec![field],
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+        None => KEY_METADATA_FIELDS.to_vec(),
+    };
+    let conditions: Vec<String> = fields.iter().map(|field| format!("{} IS NULL", field)).collect();
+    let query = format!(
+        "SELECT {} FROM wine_ratings WHERE tenant_id = ? AND ({}) ORDER BY id",
+        WINE_COLUMNS,
+        conditions.join(" OR ")
+    );
+
+    let wines = sqlx::query_as::<_, Wine>(&query)
+        .bind(&tenant.0)
+        .fetch_all(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let incomplete = wines
+        .into_iter()
+        .map(|wine| {
+            let missing = [
+                ("region", wine.region.is_none()),
+                ("variety", wine.variety.is_none()),
+                ("rating", wine.rating.is_none()),
+            ]
+            .into_iter()
+            .filter(|(_, missing)| *missing)
+            .map(|(field, _)| field.to_string())
+            .collect();
+            IncompleteWine { wine, missing }
+        })
+        .collect();
+
+    Ok(Json(incomplete))
+}
+
+fn percentile_of_sorted(sorted: &[f64], percentile: f64) -> Option<f64> {
+    let last = sorted.len().checked_sub(1)?;
+    let position = percentile / 100.0 * last as f64;
+    let lower = position.floor() as usize;
+    let upper = position.ceil() as usize;
+    let fraction = position - lower as f64;
+
+    Some(sorted[lower] + (sorted[upper] - sorted[lower]) * fraction)
+}
+
+async fn get_region_medians(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> Result<Json<BTreeMap<String, RegionMedian>>, StatusCode> {
+    let rows = sqlx::query_as::<_, (String, f64)>(
+        "SELECT region, rating FROM wine_ratings
+        WHERE tenant_id = ? AND region IS NOT NULL AND rating IS NOT NULL
+        ORDER BY region, rating",
+    )
+    .bind(&tenant.0)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut ratings: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    for (region, rating) in rows {
+        ratings.entry(region).or_default().push(rating);
+    }
+
+    // The 50th percentile of sorted ratings is the middle one, or the mean of the middle two.
+    Ok(Json(
+        ratings
+            .into_iter()
+            .filter_map(|(region, sorted)| {
+                let median = percentile_of_sorted(&sorted, 50.0)?;
+                let median = round_rating(median, config.rating_decimals);
+                Some((region, RegionMedian { median, count: sorted.len() }))
+            })
+            .collect(),
+    ))
+}
+
+async fn get_rating_percentile(
+    Path(percentile): Path<f64>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<RatingPercentile>, StatusCode> {
+    if !(0.0..=100.0).contains(&percentile) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let ratings = sqlx::query_scalar::<_, f64>(
+        "SELECT rating FROM wine_ratings WHERE tenant_id = ? AND rating IS NOT NULL ORDER BY rating",
+    )
+    .bind(&tenant.0)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let rating = percentile_of_sorted(&ratings, percentile).ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(RatingPercentile {
+        percentile,
+        rating,
+        count: ratings.len(),
+    }))
+}
+
+async fn fetch_region_counts(pool: &SqlitePool, tenant: &Tenant) -> Result<Vec<(String, i64)>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT region, COUNT(*) as count FROM wine_ratings WHERE tenant_id = ? AND region IS NOT NULL GROUP BY region",
+    )
+    .bind(&tenant.0)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.into_iter().map(|row| (row.get("region"), row.get("count"))).collect())
+}
+
+async fn fetch_variety_stats(pool: &SqlitePool, tenant: &Tenant) -> Result<Vec<(String, VarietyInfo)>, sqlx::Error> {
+    let rows = sqlx::query("SELECT variety, COUNT(*) as count, AVG(rating) as avg_rating FROM wine_ratings WHERE tenant_id = ? AND variety IS NOT NULL AND rating IS NOT NULL GROUP BY variety")
+        .bind(&tenant.0)
+        .fetch_all(pool)
+        .await?;
+
+    let mut varieties = Vec::new();
+    for row in rows {
+        let variety: String = row.get("variety");
+        let count: i64 = row.get("count");
+        let avg_rating: f64 = row.get("avg_rating");
+        varieties.push((variety, VarietyInfo { count, avg_rating }));
+    }
+
+    Ok(varieties)
+}
+
+fn partial_stat<T>(name: &str, result: Result<T, sqlx::Error>, warnings: &mut Vec<String>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(err) => {
+            tracing::warn!("stat {} failed: {}", name, err);
+            warnings.push(format!("{} could not be computed", name));
+            None
+        }
+    }
+}
+
+async fn get_stats_overview(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> Json<StatsOverview> {
+    let (totals, regions, varieties, reviews) = tokio::join!(
+        sqlx::query_as::<_, (i64, Option<f64>)>("SELECT COUNT(*), AVG(rating) FROM wine_ratings WHERE tenant_id = ?")
+            .bind(&tenant.0)
+            .fetch_one(&pool),
+        fetch_region_counts(&pool, &tenant),
+        fetch_variety_stats(&pool, &tenant),
+        sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM reviews WHERE wine_id IN (SELECT id FROM wine_ratings WHERE tenant_id = ?)",
+        )
+        .bind(&tenant.0)
+        .fetch_one(&pool),
+    );
+
+    let mut warnings = Vec::new();
+    let totals = partial_stat("total_wines", totals, &mut warnings);
+    let regions = partial_stat("regions", regions, &mut warnings);
+    let varieties = partial_stat("varieties", varieties, &mut warnings);
+    let total_reviews = partial_stat("total_reviews", reviews, &mut warnings);
+
+    Json(StatsOverview {
+        total_wines: totals.map(|(count, _)| count),
+        average_rating: totals.map(|(_, average)| average.map(|average| round_rating(average, config.rating_decimals))),
+        regions: regions.map(|regions| regions.into_iter().collect()),
+        varieties: varieties.map(|varieties| {
+            varieties
+                .into_iter()
+                .map(|(variety, info)| {
+                    let avg_rating = round_rating(info.avg_rating, config.rating_decimals);
+                    (variety, VarietyInfo { avg_rating, ..info })
+                })
+                .collect()
+        }),
+        total_reviews,
+        warnings,
+    })
+}
+
+async fn check_integrity(
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<IntegrityReport>, StatusCode> {
+    let orphaned_reviews = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM reviews r WHERE NOT EXISTS (SELECT 1 FROM wine_ratings w WHERE w.id = r.wine_id)",
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let orphaned_tags = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM wine_tags t WHERE NOT EXISTS (SELECT 1 FROM wine_ratings w WHERE w.id = t.wine_id)",
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(IntegrityReport {
+        orphaned_reviews,
+        orphaned_tags,
+        healthy: orphaned_reviews == 0 && orphaned_tags == 0,
+    }))
+}
+
+async fn database_size(pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar("SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()")
+        .fetch_one(pool)
+        .await
+}
+
+async fn optimize_database(
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(maintenance): axum::extract::State<MaintenanceLock>,
+) -> Result<Json<OptimizeReport>, StatusCode> {
+    let Ok(_guard) = maintenance.0.try_lock() else {
+        return Err(StatusCode::CONFLICT);
+    };
+
+    let before = database_size(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    sqlx::query("VACUUM")
+        .execute(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    sqlx::query("PRAGMA optimize")
+        .execute(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let after = database_size(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(OptimizeReport {
+        freed_bytes: (before - after).max(0),
+    }))
+}
+
+// VACUUM INTO copies from a single read snapshot, so with WAL journaling writers keep
+// going while the backup is taken instead of waiting for the whole copy.
+async fn backup_database(
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Response, StatusCode> {
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SAVED_FILTER_ID_LENGTH)
+        .map(char::from)
+        .collect();
+    let path = std::env::temp_dir().join(format!("wine-api-backup-{}-{}.sqlite", std::process::id(), suffix));
+    sqlx::query("VACUUM INTO ?")
+        .bind(path.to_string_lossy().into_owned())
+        .execute(&pool)
+        .await
+        .map_err(database_error)?;
+
+    let file = tokio::fs::File::open(&path).await;
+    // The open handle keeps the data readable, so the copy never outlives the response.
+    if let Err(err) = tokio::fs::remove_file(&path).await {
+        tracing::warn!("failed to remove backup file {}: {}", path.display(), err);
+    }
+    // VACUUM INTO opens its target with the connection's flags, and sqlx opens in-memory databases
+    // with SQLITE_OPEN_MEMORY, so their copy lands in memory and there is no file to open here.
+    let file = file.map_err(|err| {
+        tracing::warn!("backup file {} could not be opened: {}", path.display(), err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/vnd.sqlite3"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"wine_ratings-backup.sqlite\""),
+        ],
+        axum::body::Body::from_stream(tokio_util::io::ReaderStream::new(file)),
+    )
+        .into_response())
+}
+
+async fn assign_tag(
+    Path(tag): Path<String>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+    Json(filters): Json<WineFilters>,
+) -> Result<Json<TagAssignment>, StatusCode> {
+    let tag = tag.trim().to_string();
+    if tag.is_empty() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let result = retry_transient(&config, || async {
+        let mut query = QueryBuilder::<Sqlite>::new("INSERT OR IGNORE INTO wine_tags (wine_id, tag) SELECT id, ");
+        query.push_bind(tag.clone()).push(" FROM wine_ratings");
+        push_filter_conditions(&mut query, &tenant, &filters);
+
+        let mut tx = pool.begin().await?;
+        let result = query.build().execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(result)
+    })
+    .await
+    .map_err(database_error)?;
+
+    Ok(Json(TagAssignment {
+        tag,
+        tagged: result.rows_affected(),
+    }))
+}
+
+async fn get_tagged_wines(
+    Path(tag): Path<String>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<Vec<Wine>>, StatusCode> {
+    let wines = sqlx::query_as::<_, Wine>(&format!(
+        "SELECT {} FROM wine_ratings
+        WHERE tenant_id = ? AND id IN (SELECT wine_id FROM wine_tags WHERE tag = ?) ORDER BY id",
+        WINE_COLUMNS
+    ))
+    .bind(&tenant.0)
+    .bind(tag.trim())
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(wines))
+}
+
+async fn add_favorite(
+    Path(id): Path<i64>,
+    tenant: Tenant,
+    user: CurrentUser,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> Result<StatusCode, StatusCode> {
+    fetch_wine(&pool, &tenant, id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    retry_transient(&config, || {
+        sqlx::query("INSERT OR IGNORE INTO favorites (tenant_id, user_id, wine_id) VALUES (?, ?, ?)")
+            .bind(&tenant.0)
+            .bind(&user.0)
+            .bind(id)
+            .execute(&pool)
+    })
+    .await
+    .map_err(database_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_favorite(
+    Path(id): Path<i64>,
+    tenant: Tenant,
+    user: CurrentUser,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> StatusCode {
+    let result = sqlx::query("DELETE FROM favorites WHERE tenant_id = ? AND user_id = ? AND wine_id = ?")
+        .bind(&tenant.0)
+        .bind(&user.0)
+        .bind(id)
+        .execute(&pool)
+        .await;
+
+    match result {
+        Ok(result) if result.rows_affected() > 0 => StatusCode::NO_CONTENT,
+        Ok(_) => StatusCode::NOT_FOUND,
+        Err(err) => database_error(err),
+    }
+}
+
+async fn get_favorites(
+    tenant: Tenant,
+    user: CurrentUser,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<Vec<Wine>>, StatusCode> {
+    let wines = sqlx::query_as::<_, Wine>(&format!(
+        "SELECT {} FROM wine_ratings
+        WHERE tenant_id = ? AND id IN (SELECT wine_id FROM favorites WHERE tenant_id = ? AND user_id = ?) ORDER BY id",
+        WINE_COLUMNS
+    ))
+    .bind(&tenant.0)
+    .bind(&tenant.0)
+    .bind(&user.0)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(wines))
+}
+
+// Each favorite votes for its variety and its region, so affinity counts how many of the
+// user's favorites a candidate shares a variety or region with.
+async fn get_recommendations(
+    Query(params): Query<RecommendationQuery>,
+    tenant: Tenant,
+    user: CurrentUser,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<Vec<Recommendation>>, StatusCode> {
+    let limit = params.limit.unwrap_or(DEFAULT_RECOMMENDATIONS);
+    if limit < 1 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let recommendations = sqlx::query_as::<_, Recommendation>(&format!(
+        "WITH favorite_wines AS (
+            SELECT w.variety, w.region FROM favorites f JOIN wine_ratings w ON w.id = f.wine_id
+            WHERE f.tenant_id = ? AND f.user_id = ?
+        )
+        SELECT * FROM (
+            SELECT {},
+                (SELECT COUNT(*) FROM favorite_wines fw WHERE fw.variety = wine_ratings.variety)
+                + (SELECT COUNT(*) FROM favorite_wines fw WHERE fw.region = wine_ratings.region) AS affinity
+            FROM wine_ratings
+            WHERE tenant_id = ? AND rating >= ?
+                AND id NOT IN (SELECT wine_id FROM favorites WHERE tenant_id = ? AND user_id = ?)
+        )
+        WHERE affinity > 0 ORDER BY affinity DESC, rating DESC, id LIMIT ?",
+        WINE_COLUMNS
+    ))
+    .bind(&tenant.0)
+    .bind(&user.0)
+    .bind(&tenant.0)
+    .bind(RECOMMENDATION_MIN_RATING)
+    .bind(&tenant.0)
+    .bind(&user.0)
+    .bind(limit)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(recommendations))
+}
+
+async fn get_regions(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<BTreeMap<String, i64>>, StatusCode> {
+    let regions = fetch_region_counts(&pool, &tenant)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(regions.into_iter().collect()))
+}
+
+async fn get_varieties(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> Result<Json<BTreeMap<String, VarietyInfo>>, StatusCode> {
+    let varieties = fetch_variety_stats(&pool, &tenant)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(
+        varieties
+            .into_iter()
+            .map(|(variety, info)| {
+                let avg_rating = round_rating(info.avg_rating, config.rating_decimals);
+                (variety, VarietyInfo { avg_rating, ..info })
+            })
+            .collect(),
+    ))
+}
+
+async fn get_variety_leaders(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<BTreeMap<String, VarietyLeader>>, StatusCode> {
+    let rows = sqlx::query(concat!(
+        "SELECT variety, id, name, rating FROM (
+            SELECT variety, id, name, rating,
+                ROW_NUMBER() OVER (PARTITION BY variety ORDER BY ",
+        normalized_rating!(),
+        " DESC, id) AS position
+            FROM wine_ratings WHERE tenant_id = ? AND variety IS NOT NULL AND rating IS NOT NULL
+        ) WHERE position = 1",
+    ))
+    .bind(&tenant.0)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(
+        rows.into_iter()
+            .map(|row| {
+                let leader = VarietyLeader {
+                    id: row.get("id"),
+                    name: row.get("name"),
+                    rating: row.get("rating"),
+                };
+                (row.get("variety"), leader)
+            })
+            .collect(),
+    ))
+}
+
+fn fold_search_text(text: &str) -> String {
+    text.nfd().filter(|c| !is_combining_mark(*c)).collect::<String>().to_lowercase()
+}
+
+fn normalize_search_term(folded_term: &str) -> String {
+    folded_term.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+async fn log_search(pool: &SqlitePool, tenant: &Tenant, folded_term: &str) {
+    let term = normalize_search_term(folded_term);
+    if term.is_empty() {
+        return;
+    }
+    // Analytics must never fail the search itself.
+    if let Err(err) = sqlx::query("INSERT INTO search_log (tenant_id, term) VALUES (?, ?)")
+        .bind(&tenant.0)
+        .bind(&term)
+        .execute(pool)
+        .await
+    {
+        tracing::warn!("failed to log search term: {}", err);
+    }
+}
+
+fn search_window_modifier(window: &str) -> Option<String> {
+    [("d", "days"), ("h", "hours"), ("m", "minutes")]
+        .into_iter()
+        .find_map(|(suffix, unit)| {
+            let amount: u32 = window.strip_suffix(suffix)?.parse().ok().filter(|amount| *amount > 0)?;
+            Some(format!("-{} {}", amount, unit))
+        })
+}
+
+async fn get_popular_searches(
+    Query(query): Query<PopularSearchQuery>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<Vec<PopularSearch>>, StatusCode> {
+    let window = query.window.as_deref().unwrap_or(DEFAULT_SEARCH_WINDOW);
+    let modifier = search_window_modifier(window.trim()).ok_or(StatusCode::BAD_REQUEST)?;
+    let limit = query.limit.unwrap_or(DEFAULT_POPULAR_SEARCHES);
+    if limit < 1 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let rows = sqlx::query(
+        "SELECT term, COUNT(*) AS count FROM search_log
+        WHERE tenant_id = ? AND searched_at >= datetime('now', ?)
+        GROUP BY term ORDER BY count DESC, term LIMIT ?",
+    )
+    .bind(&tenant.0)
+    .bind(modifier)
+    .bind(limit)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(
+        rows.into_iter()
+            .map(|row| PopularSearch {
+                term: row.get("term"),
+                count: row.get("count"),
+            })
+            .collect(),
+    ))
+}
+
+async fn get_wines_by_flavor(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    Json(flavors): Json<Vec<String>>,
+) -> Result<Json<Vec<FlavorMatch>>, StatusCode> {
+    let mut flavors: Vec<String> = flavors
+        .iter()
+        .map(|flavor| fold_search_text(flavor.trim()))
+        .filter(|flavor| !flavor.is_empty())
+        .collect();
+    flavors.sort();
+    flavors.dedup();
+    if flavors.is_empty() {
+        return Ok(Json(Vec::new()));
+    }
+
+    let wines = sqlx::query_as::<_, Wine>(&format!(
+        "SELECT {} FROM wine_ratings WHERE tenant_id = ? AND notes IS NOT NULL ORDER BY id",
+        WINE_COLUMNS
+    ))
+    .bind(&tenant.0)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut matches: Vec<FlavorMatch> = wines
+        .into_iter()
+        .filter_map(|wine| {
+            let notes = fold_search_text(wine.notes.as_deref()?);
+            let matched_flavors = flavors.iter().filter(|flavor| notes.contains(flavor.as_str())).count();
+            (matched_flavors > 0).then_some(FlavorMatch { wine, matched_flavors })
+        })
+        .collect();
+    matches.sort_by(|a, b| b.matched_flavors.cmp(&a.matched_flavors).then(a.wine.id.cmp(&b.wine.id)));
+
+    Ok(Json(matches))
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn suggest_wine_name<'a>(names: &'a [(i64, String)], folded_term: &str) -> Option<&'a str> {
+    let term_length = folded_term.chars().count();
+    names
+        .iter()
+        .filter_map(|(id, name)| {
+            let folded_name = fold_search_text(name);
+            let distance = std::iter::once(folded_name.as_str())
+                .chain(folded_name.split_whitespace())
+                .map(|candidate| edit_distance(folded_term, candidate))
+                .min()?;
+            Some((distance, *id, name.as_str()))
+        })
+        .filter(|&(distance, _, _)| distance <= SUGGESTION_MAX_DISTANCE && distance < term_length)
+        .min()
+        .map(|(_, _, name)| name)
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Folds character by character, remembering which original byte offset produced each folded
+// byte, so matches found in the folded text can be mapped back to the original.
+fn fold_with_origins(text: &str) -> (String, Vec<usize>) {
+    let mut folded = String::new();
+    let mut origins = Vec::new();
+    for (offset, c) in text.char_indices() {
+        let folded_char = fold_search_text(c.encode_utf8(&mut [0; 4]));
+        origins.extend(std::iter::repeat_n(offset, folded_char.len()));
+        folded.push_str(&folded_char);
+    }
+    (folded, origins)
+}
+
+// The first match with up to SNIPPET_CONTEXT_CHARS characters either side, marking cut ends
+// with an ellipsis.
+fn match_snippet(text: &str, folded_term: &str) -> Option<String> {
+    let (folded, origins) = fold_with_origins(text);
+    let folded_start = folded.find(folded_term)?;
+    let start = origins[folded_start];
+    let end = origins.get(folded_start + folded_term.len()).copied().unwrap_or(text.len());
+
+    let before: Vec<(usize, char)> = text[..start].char_indices().collect();
+    let snippet_start = before.len().checked_sub(SNIPPET_CONTEXT_CHARS).map_or(0, |index| before[index].0);
+    let snippet_end = text[end..]
+        .char_indices()
+        .nth(SNIPPET_CONTEXT_CHARS)
+        .map_or(text.len(), |(offset, _)| end + offset);
+
+    let mut snippet = String::new();
+    if snippet_start > 0 {
+        snippet.push('…');
+    }
+    snippet.push_str(text[snippet_start..snippet_end].trim());
+    if snippet_end < text.len() {
+        snippet.push('…');
+    }
+    Some(snippet)
+}
+
+fn highlight_matches(text: &str, folded_term: &str) -> String {
+    if folded_term.is_empty() {
+        return escape_html(text);
+    }
+
+    let (folded, origins) = fold_with_origins(text);
+    let mut highlighted = String::new();
+    let mut copied = 0;
+    let mut search_from = 0;
+    while let Some(position) = folded[search_from..].find(folded_term) {
+        let folded_start = search_from + position;
+        let folded_end = folded_start + folded_term.len();
+        let start = origins[folded_start];
+        let end = origins.get(folded_end).copied().unwrap_or(text.len());
+
+        highlighted.push_str(&escape_html(&text[copied..start]));
+        highlighted.push_str("<mark>");
+        highlighted.push_str(&escape_html(&text[start..end]));
+        highlighted.push_str("</mark>");
+        copied = end;
+        search_from = folded_end;
+    }
+    highlighted.push_str(&escape_html(&text[copied..]));
+
+    highlighted
+}
+
+async fn search_wines(
+    Query(search): Query<SearchQuery>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> Result<Response, StatusCode> {
+    // instr() rather than LIKE, so % and _ in the term are matched literally.
+    let condition = format!(
+        "tenant_id = ? AND (instr({name}, ?) > 0 OR instr({notes}, ?) > 0)",
+        name = NAME_SEARCH,
+        notes = NOTES_SEARCH,
+    );
+    let folded_term = fold_search_text(&search.q);
+    log_search(&pool, &tenant, &folded_term).await;
+
+    let (limit, offset) = match search_page_bounds(search.limit, search.offset, &config) {
+        Ok(page) => page,
+        Err(err) => return Ok(err.into_response()),
+    };
+
+    let matching = sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) FROM wine_ratings WHERE {}", condition))
+        .bind(&tenant.0)
+        .bind(&folded_term)
+        .bind(&folded_term)
+        .fetch_one(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Hard ceiling on top of pagination, so a one-letter term cannot page through the whole
+    // catalog: no page reaches past the first MAX_SEARCH_RESULTS matches, though X-Total-Count
+    // still reports every match.
+    let cap = config.max_search_results as i64;
+    let truncated = matching > cap;
+    let matches = sqlx::query_as::<_, Wine>(&format!(
+        "SELECT {} FROM wine_ratings WHERE {} ORDER BY instr({}, ?) = 0, id LIMIT ? OFFSET ?",
+        WINE_COLUMNS, condition, NAME_SEARCH
+    ))
+    .bind(&tenant.0)
+    .bind(&folded_term)
+    .bind(&folded_term)
+    .bind(&folded_term)
+    .bind(limit.min(cap - offset).max(0))
+    .bind(offset)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Only an empty result needs the catalog's names, to look for a near miss.
+    let suggestion = if matching == 0 {
+        let names = sqlx::query_as::<_, (i64, String)>("SELECT id, name FROM wine_ratings WHERE tenant_id = ?")
+            .bind(&tenant.0)
+            .fetch_all(&pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        suggest_wine_name(&names, &folded_term).map(str::to_string)
+    } else {
+        None
+    };
+
+    if search.highlight.unwrap_or(false) {
+        let results: Vec<HighlightedWine> = matches
+            .into_iter()
+            .map(|wine| HighlightedWine {
+                highlighted_name: highlight_matches(&wine.name, &folded_term),
+                highlighted_notes: wine.notes.as_deref().map(|notes| highlight_matches(notes, &folded_term)),
+                wine,
+            })
+            .collect();
+        return Ok(search_response(results, matching, truncated, suggestion.as_deref()));
+    }
+
+    Ok(search_response(matches, matching, truncated, suggestion.as_deref()))
+}
+
+async fn search_reviews(
+    Query(search): Query<SearchQuery>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> Result<Response, StatusCode> {
+    let folded_term = fold_search_text(&search.q);
+    if folded_term.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let (limit, offset) = match search_page_bounds(search.limit, search.offset, &config) {
+        Ok(page) => page,
+        Err(err) => return Ok(err.into_response()),
+    };
+
+    let matching_reviews = format!(
+        "SELECT r.wine_id, MIN(r.id) AS first_review, COUNT(*) AS matching_reviews FROM reviews r
+        JOIN wine_ratings w ON w.id = r.wine_id
+        WHERE w.tenant_id = ? AND instr({}, ?) > 0
+        GROUP BY r.wine_id",
+        COMMENT_SEARCH
+    );
+    let matching = sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) FROM ({})", matching_reviews))
+        .bind(&tenant.0)
+        .bind(&folded_term)
+        .fetch_one(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let cap = config.max_search_results as i64;
+    let truncated = matching > cap;
+
+    // One result per wine, in order of its earliest matching review, quoting that review.
+    let rows = sqlx::query_as::<_, ReviewMatchRow>(&format!(
+        "WITH matched AS ({})
+        SELECT {}, matched.matching_reviews,
+            (SELECT comment FROM reviews WHERE id = matched.first_review) AS first_comment
+        FROM matched JOIN wine_ratings ON wine_ratings.id = matched.wine_id
+        ORDER BY matched.first_review LIMIT ? OFFSET ?",
+        matching_reviews, WINE_COLUMNS
+    ))
+    .bind(&tenant.0)
+    .bind(&folded_term)
+    .bind(limit.min(cap - offset).max(0))
+    .bind(offset)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let results: Vec<ReviewMatch> = rows
+        .into_iter()
+        .map(|row| ReviewMatch {
+            snippet: match_snippet(&row.first_comment, &folded_term).unwrap_or(row.first_comment),
+            wine: row.wine,
+            matching_reviews: row.matching_reviews,
+        })
+        .collect();
+    Ok(search_response(results, matching, truncated, None))
+}
+
+async fn get_wines_by_region(
+    Path(region): Path<String>,
+    Query(params): Query<RegionQuery>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> Result<Json<Vec<Wine>>, StatusCode> {
+    let wines = sqlx::query_as::<_, Wine>(&format!(
+        "SELECT {} FROM wine_ratings WHERE tenant_id = ? AND region = ?",
+        WINE_COLUMNS
+    ))
+    .bind(&tenant.0)
+    .bind(region)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if wines.is_empty() && params.strict.unwrap_or(config.strict_region_lookup) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    
+    Ok(Json(wines))
+}
+
+async fn get_region_trend(
+    tenant: Tenant,
+    Path(region): Path<String>,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> Result<Json<Vec<VintageTrend>>, StatusCode> {
+    let known: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM wine_ratings WHERE tenant_id = ? AND region = ?)")
+        .bind(&tenant.0)
+        .bind(&region)
+        .fetch_one(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !known {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let rows = sqlx::query(
+        "SELECT vintage, AVG(rating) AS avg_rating, COUNT(*) AS count FROM wine_ratings
+        WHERE tenant_id = ? AND region = ? AND vintage IS NOT NULL AND rating IS NOT NULL
+        GROUP BY vintage ORDER BY vintage",
+    )
+    .bind(&tenant.0)
+    .bind(&region)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(
+        rows.into_iter()
+            .map(|row| VintageTrend {
+                vintage: row.get("vintage"),
+                avg_rating: round_rating(row.get("avg_rating"), config.rating_decimals),
+                count: row.get("count"),
+            })
+            .collect(),
+    ))
+}
+
+// Bayesian average: (prior * catalog mean + sum of ratings) / (prior + count), so a region
+// needs several good wines before it can outrank well-sampled ones.
+fn bayesian_average(sum: f64, count: f64, mean: f64, prior: f64) -> f64 {
+    (prior * mean + sum) / (prior + count)
+}
+
+async fn get_region_ranking(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> Result<Json<Vec<RegionRanking>>, StatusCode> {
+    let rows = sqlx::query_as::<_, (String, i64, f64)>(
+        "SELECT region, COUNT(*), SUM(rating) FROM wine_ratings
+        WHERE tenant_id = ? AND region IS NOT NULL AND rating IS NOT NULL
+        GROUP BY region",
+    )
+    .bind(&tenant.0)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let total: i64 = rows.iter().map(|(_, count, _)| count).sum();
+    let mean = rows.iter().map(|(_, _, sum)| sum).sum::<f64>() / total.max(1) as f64;
+    let mut rankings: Vec<RegionRanking> = rows
+        .into_iter()
+        .map(|(region, count, sum)| RegionRanking {
+            region,
+            score: bayesian_average(sum, count as f64, mean, REGION_RANKING_PRIOR_WEIGHT),
+            avg_rating: sum / count as f64,
+            count,
+        })
+        .collect();
+    rankings.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.region.cmp(&b.region)));
+    for ranking in &mut rankings {
+        ranking.score = round_rating(ranking.score, config.rating_decimals);
+        ranking.avg_rating = round_rating(ranking.avg_rating, config.rating_decimals);
+    }
+
+    Ok(Json(rankings))
+}
+
+type WineSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+#[derive(Debug, Default, InputObject)]
+struct WineFilterInput {
+    region: Option<String>,
+    region_exact: Option<String>,
+    exclude_region: Option<String>,
+    variety: Option<String>,
+    variety_exact: Option<String>,
+    min_rating: Option<f64>,
+    max_rating: Option<f64>,
+}
+
+impl From<WineFilterInput> for WineFilters {
+    fn from(input: WineFilterInput) -> Self {
+        Self {
+            region: input.region,
+            region_exact: input.region_exact,
+            exclude_region: input.exclude_region,
+            variety: input.variety,
+            variety_exact: input.variety_exact,
+            min_rating: input.min_rating,
+            max_rating: input.max_rating,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug, SimpleObject)]
+struct RegionCount {
+    region: String,
+    count: i64,
+}
+
+#[derive(Debug, SimpleObject)]
+struct VarietyStats {
+    variety: String,
+    count: i64,
+    avg_rating: f64,
+}
+
+struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn wines(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<WineFilterInput>,
+        limit: Option<i64>,
+        offset: Option<i64>,
+    ) -> async_graphql::Result<Vec<Wine>> {
+        let pool = ctx.data::<SqlitePool>()?;
+        let tenant = ctx.data::<Tenant>()?;
+        let filters = filter.map(WineFilters::from).unwrap_or_default();
+        let mut query = filtered_query(&format!("SELECT {} FROM wine_ratings", WINE_COLUMNS), tenant, &filters)?;
+        let config = ctx.data::<Arc<Config>>()?;
+        push_page(&mut query, page_bounds(limit, offset, config)?);
+
+        Ok(query.build_query_as::<Wine>().fetch_all(pool).await?)
+    }
+
+    async fn wine(&self, ctx: &Context<'_>, id: i64) -> async_graphql::Result<Option<Wine>> {
+        let pool = ctx.data::<SqlitePool>()?;
+        let tenant = ctx.data::<Tenant>()?;
+
+        Ok(fetch_wine(pool, tenant, id).await?)
+    }
+
+    async fn regions(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<RegionCount>> {
+        let pool = ctx.data::<SqlitePool>()?;
+        let regions = fetch_region_counts(pool, ctx.data::<Tenant>()?).await?;
+
+        Ok(regions
+            .into_iter()
+            .map(|(region, count)| RegionCount { region, count })
+            .collect())
+    }
+
+    async fn varieties(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<VarietyStats>> {
+        let pool = ctx.data::<SqlitePool>()?;
+        let config = ctx.data::<Arc<Config>>()?;
+        let varieties = fetch_variety_stats(pool, ctx.data::<Tenant>()?).await?;
+
+        Ok(varieties
+            .into_iter()
+            .map(|(variety, info)| VarietyStats {
+                variety,
+                count: info.count,
+                avg_rating: round_rating(info.avg_rating, config.rating_decimals),
+            })
+            .collect())
+    }
+}
+
+async fn graphql_handler(
+    tenant: Tenant,
+    axum::extract::State(schema): axum::extract::State<WineSchema>,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    Json(schema.execute(request.data(tenant)).await)
+}
+
+async fn graphql_playground() -> Html<String> {
+    Html(playground_source(GraphQLPlaygroundConfig::new("/graphql")))
+}
+
+async fn get_wines_starting_with(
+    Path(letter): Path<String>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<Vec<Wine>>, StatusCode> {
+    let mut chars = letter.chars();
+    let (Some(first), None) = (chars.next(), chars.next()) else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    if !first.is_alphabetic() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let wines = sqlx::query_as::<_, Wine>(&format!(
+        "SELECT {} FROM wine_ratings WHERE tenant_id = ? AND name LIKE ? || '%' ORDER BY name COLLATE NOCASE, id",
+        WINE_COLUMNS
+    ))
+    .bind(&tenant.0)
+    .bind(first.to_string())
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(wines))
+}
+
+async fn get_wines_in_bbox(
+    Query(bbox): Query<BoundingBox>,
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<Vec<Wine>>, StatusCode> {
+    if bbox.min_lat > bbox.max_lat
+        || bbox.min_lng > bbox.max_lng
+        || !is_valid_coordinates(Some(bbox.min_lat), Some(bbox.min_lng))
+        || !is_valid_coordinates(Some(bbox.max_lat), Some(bbox.max_lng))
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let wines = sqlx::query_as::<_, Wine>(&format!(
+        "SELECT {} FROM wine_ratings
+        WHERE tenant_id = ? AND latitude BETWEEN ? AND ? AND longitude BETWEEN ? AND ? ORDER BY id",
+        WINE_COLUMNS
+    ))
+    .bind(&tenant.0)
+    .bind(bbox.min_lat)
+    .bind(bbox.max_lat)
+    .bind(bbox.min_lng)
+    .bind(bbox.max_lng)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(wines))
+}
+
+async fn get_wines_geojson(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Response, StatusCode> {
+    let wines = sqlx::query_as::<_, Wine>(&format!(
+        "SELECT {} FROM wine_ratings
+        WHERE tenant_id = ? AND latitude IS NOT NULL AND longitude IS NOT NULL ORDER BY id",
+        WINE_COLUMNS
+    ))
+    .bind(&tenant.0)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let features = wines
+        .into_iter()
+        .filter_map(|wine| {
+            let coordinates = [wine.longitude?, wine.latitude?];
+            Some(GeoJsonFeature {
+                kind: "Feature",
+                id: wine.id,
+                geometry: GeoJsonPoint { kind: "Point", coordinates },
+                properties: GeoJsonProperties {
+                    name: wine.name,
+                    region: wine.region,
+                    rating: wine.rating,
+                },
+            })
+        })
+        .collect();
+    let collection = GeoJsonFeatureCollection {
+        kind: "FeatureCollection",
+        features,
+    };
+    let body = serde_json::to_string(&collection).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(([(header::CONTENT_TYPE, "application/geo+json")], body).into_response())
+}
+
+fn alpha_bucket(name: &str) -> String {
+    match name.trim_start().chars().next() {
+        Some(first) if first.is_alphabetic() => fold_search_text(&first.to_string()).to_uppercase(),
+        _ => "#".to_string(),
+    }
+}
+
+async fn get_wines_grouped_alpha(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+) -> Result<Json<BTreeMap<String, Vec<Wine>>>, StatusCode> {
+    let wines = sqlx::query_as::<_, Wine>(&format!(
+        "SELECT {} FROM wine_ratings WHERE tenant_id = ? ORDER BY name COLLATE NOCASE, id",
+        WINE_COLUMNS
+    ))
+    .bind(&tenant.0)
+    .fetch_all(&pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut grouped: BTreeMap<String, Vec<Wine>> = BTreeMap::new();
+    for wine in wines {
+        grouped.entry(alpha_bucket(&wine.name)).or_default().push(wine);
+    }
+
+    Ok(Json(grouped))
+}
+
+async fn get_wines_by_regions(
+    tenant: Tenant,
+    axum::extract::State(pool): axum::extract::State<SqlitePool>,
+    Json(regions): Json<Vec<String>>,
+) -> Result<Json<BTreeMap<String, Vec<Wine>>>, StatusCode> {
+    let mut grouped: BTreeMap<String, Vec<Wine>> = regions.iter().map(|region| (region.clone(), Vec::new())).collect();
+    if regions.is_empty() {
+        return Ok(Json(grouped));
+    }
+
+    let mut query = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM wine_ratings WHERE tenant_id = ", WINE_COLUMNS));
+    query.push_bind(tenant.0.clone()).push(" AND region IN (");
+    let mut separated = query.separated(", ");
+    for region in &regions {
+        separated.push_bind(region.clone());
+    }
+    query.push(") ORDER BY id");
+
+    let wines = query
+        .build_query_as::<Wine>()
+        .fetch_all(&pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    for wine in wines {
+        if let Some(region) = &wine.region {
+            grouped.entry(region.clone()).or_default().push(wine);
+        }
+    }
+
+    Ok(Json(grouped))
+}
+
+async fn count_requests(
+    axum::extract::State(requests): axum::extract::State<RequestCounter>,
+    request: Request,
+    next: Next,
+) -> Response {
+    requests.0.fetch_add(1, Ordering::Relaxed);
+    next.run(request).await
+}
+
+#[derive(Clone)]
+struct ConcurrencyLimit {
+    permits: Option<Arc<tokio::sync::Semaphore>>,
+    overflow: ConcurrencyOverflow,
+}
+
+impl ConcurrencyLimit {
+    fn new(config: &Config) -> Self {
+        let permits = (config.max_concurrent_requests > 0)
+            .then(|| Arc::new(tokio::sync::Semaphore::new(config.max_concurrent_requests)));
+        Self {
+            permits,
+            overflow: config.concurrency_overflow,
+        }
+    }
+}
+
+// Queued requests wait for a permit (still bounded by the request timeout); rejected ones get
+// a 503, which add_retry_after turns into a retry hint.
+async fn limit_concurrency(
+    axum::extract::State(limit): axum::extract::State<ConcurrencyLimit>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(permits) = limit.permits else {
+        return next.run(request).await;
+    };
+    let permit = match limit.overflow {
+        ConcurrencyOverflow::Queue => permits.acquire_owned().await.ok(),
+        ConcurrencyOverflow::Reject => permits.try_acquire_owned().ok(),
+    };
+    let Some(_permit) = permit else {
+        return json_error(StatusCode::SERVICE_UNAVAILABLE, "too many concurrent requests");
+    };
+    next.run(request).await
+}
+
+async fn track_in_flight(
+    axum::extract::State(in_flight): axum::extract::State<InFlightGauge>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let _request = in_flight.enter();
+    next.run(request).await
+}
+
+async fn require_admin_token(
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // Fails closed: without a configured token there is no credential that opens these routes.
+    let authorized = config.admin_token.as_deref().is_some_and(|token| {
+        request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|provided| provided == token)
+    });
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    next.run(request).await
+}
+
+async fn get_request_stats(
+    axum::extract::State(requests): axum::extract::State<RequestCounter>,
+) -> Json<RequestStats> {
+    Json(RequestStats {
+        total_requests: requests.0.load(Ordering::Relaxed),
+    })
+}
+
+async fn get_pool_stats(axum::extract::State(pool): axum::extract::State<SqlitePool>) -> Json<PoolStats> {
+    let size = pool.size();
+    let idle = pool.num_idle();
+    Json(PoolStats {
+        size,
+        idle,
+        // size and num_idle are sampled separately, so clamp a momentary race.
+        in_use: (size as usize).saturating_sub(idle),
+        max_connections: pool.options().get_max_connections(),
+    })
+}
+
+pub fn create_app(pool: SqlitePool) -> Router {
+    create_app_with_config(pool, &Config::default())
+}
+
+fn compression_layer(min_bytes: u16) -> CompressionLayer<impl Predicate> {
+    let predicate = SizeAbove::new(min_bytes)
+        .and(NotForContentType::GRPC)
+        .and(NotForContentType::IMAGES)
+        .and(NotForContentType::SSE);
+    CompressionLayer::new().compress_when(predicate)
+}
+
+async fn health() -> Json<HealthStatus> {
+    Json(HealthStatus {
+        status: "ok".to_string(),
+    })
+}
+
+fn cors_layer(origins: &[HeaderValue]) -> CorsLayer {
+    if origins.is_empty() {
+        return CorsLayer::permissive();
+    }
+
+    CorsLayer::new()
+        .allow_origin(origins.to_vec())
+        .allow_methods(Any)
+        .allow_headers(Any)
+}
+
+struct ApiRoute {
+    method: &'static str,
+    path: &'static str,
+    description: &'static str,
+    handler: MethodRouter<AppState>,
+}
+
+impl ApiRoute {
+    fn info(&self) -> RouteInfo {
+        RouteInfo {
+            method: self.method.to_string(),
+            path: self.path.to_string(),
+            description: self.description.to_string(),
+        }
+    }
+}
+
+fn api_route(
+    method: &'static str,
+    path: &'static str,
+    description: &'static str,
+    handler: MethodRouter<AppState>,
+) -> ApiRoute {
+    ApiRoute {
+        method,
+        path,
+        description,
+        handler,
+    }
+}
+
+// Routes sharing a path are merged by axum, so each method gets its own entry and description.
+fn into_router(routes: Vec<ApiRoute>) -> Router<AppState> {
+    routes
+        .into_iter()
+        .fold(Router::new(), |router, route| router.route(route.path, route.handler))
+}
+
+fn api_routes(pool: &SqlitePool) -> Vec<ApiRoute> {
+    vec![
+        api_route("GET", "/", "List the available API routes", get(list_routes)),
+        api_route(
+            "GET",
+            "/wines",
+            "List wines with filtering, sorting and pagination",
+            get(get_wines).route_layer(middleware::from_fn_with_state(pool.clone(), add_dataset_version)),
+        ),
+        api_route(
+            "POST",
+            "/wines",
+            "Create a wine",
+            post(create_wine).route_layer(middleware::from_fn(apply_return_preference)),
+        ),
+        api_route("PATCH", "/wines", "Set fields on every wine matching the filters", patch(bulk_patch_wines)),
+        api_route("GET", "/wines/:id", "Get a single wine by id", get(get_wine)),
+        api_route("HEAD", "/wines/:id", "Check whether a wine exists", head(wine_exists)),
+        api_route(
+            "PATCH",
+            "/wines/:id",
+            "Update some fields of a wine",
+            patch(patch_wine).route_layer(middleware::from_fn(apply_return_preference)),
+        ),
+        api_route(
+            "GET",
+            "/wines/:id/drinkability",
+            "Score how ready a wine is to drink at its current age",
+            get(get_wine_drinkability),
+        ),
+        api_route("GET", "/wines/export", "Download every wine as a JSON or CSV file", get(export_wines)),
+        api_route("GET", "/wines/:id/export.json", "Download a wine as a JSON attachment", get(export_wine)),
+        api_route("POST", "/wines/:id/reviews", "Add a review to a wine", post(create_review)),
+        api_route(
+            "GET",
+            "/wines/:id/rating-timeline",
+            "Review scores of a wine in chronological order",
+            get(get_rating_timeline),
+        ),
+        api_route(
+            "PUT",
+            "/wines/by-external/:external_id",
+            "Insert or update a wine by its external id",
+            put(upsert_wine_by_external_id).route_layer(middleware::from_fn(apply_return_preference)),
+        ),
+        api_route("GET", "/wines/search", "Search wine names and notes for keywords", get(search_wines)),
+        api_route("GET", "/reviews/search", "Find wines whose review comments mention a keyword", get(search_reviews)),
+        api_route(
+            "GET",
+            "/wines/facets",
+            "Result counts per region and variety under the current filters",
+            get(get_wine_facets),
+        ),
+        api_route("GET", "/wines/schema", "Describe each wine field", get(get_wine_schema)),
+        api_route("GET", "/wines/events", "Stream newly created wines as server-sent events", get(wine_events)),
+        api_route("GET", "/wines/duplicates", "Find wines sharing a normalized name", get(get_duplicate_wines)),
+        api_route(
+            "GET",
+            "/wines/name-collisions",
+            "Find wine names produced in more than one region",
+            get(get_name_collisions),
+        ),
+        api_route(
+            "GET",
+            "/wines/weighted-random",
+            "Pick a random wine weighted by rating",
+            get(get_weighted_random_wine),
+        ),
+        api_route("GET", "/wines/incomplete", "List wines missing region, variety or rating", get(get_incomplete_wines)),
+        api_route("GET", "/wines/outliers", "List wines with unusual ratings", get(get_rating_outliers)),
+        api_route("POST", "/wines/ratings", "Apply rating corrections in bulk", post(update_ratings)),
+        api_route(
+            "POST",
+            "/wines/import/validate",
+            "Check a CSV upload row by row without importing it",
+            post(validate_import),
+        ),
+        api_route(
+            "POST",
+            "/wines/import-url",
+            "Import wines from a CSV or JSON file at a URL",
+            post(import_wines_from_url),
+        ),
+        api_route("GET", "/wines/region/:region", "List wines from a region", get(get_wines_by_region)),
+        api_route("POST", "/wines/by-regions", "List wines for several regions at once", post(get_wines_by_regions)),
+        api_route("GET", "/wines/bbox", "List wines inside a map bounding box", get(get_wines_in_bbox)),
+        api_route("GET", "/wines/geojson", "Wines with coordinates as a GeoJSON FeatureCollection", get(get_wines_geojson)),
+        api_route("POST", "/wines/by-flavor", "Rank wines by flavor keywords in their notes", post(get_wines_by_flavor)),
+        api_route(
+            "GET",
+            "/wines/starts-with/:letter",
+            "List wines whose name starts with a letter",
+            get(get_wines_starting_with),
+        ),
+        api_route("GET", "/wines/grouped-alpha", "List wines grouped by first letter", get(get_wines_grouped_alpha)),
+        api_route("POST", "/tags/:tag/assign", "Tag every wine matching a filter", post(assign_tag)),
+        api_route("GET", "/tags/:tag/wines", "List wines carrying a tag", get(get_tagged_wines)),
+        api_route("POST", "/filters", "Save a wine filter as a shareable permalink", post(create_saved_filter)),
+        api_route("GET", "/filters/:id/wines", "List wines matching a saved filter", get(get_saved_filter_wines)),
+        api_route("GET", "/me/favorites", "List the signed-in user's favorite wines", get(get_favorites)),
+        api_route("PUT", "/me/favorites/:id", "Add a wine to the signed-in user's favorites", put(add_favorite)),
+        api_route(
+            "DELETE",
+            "/me/favorites/:id",
+            "Remove a wine from the signed-in user's favorites",
+            delete(remove_favorite),
+        ),
+        api_route(
+            "GET",
+            "/me/recommendations",
+            "Suggest wines similar to the signed-in user's favorites",
+            get(get_recommendations),
+        ),
+        api_route("GET", "/version", "Get the current dataset version", get(get_dataset_version)),
+        api_route("GET", "/regions", "Count wines per region", get(get_regions)),
+        api_route("GET", "/regions/medians", "Median rating per region", get(get_region_medians)),
+        api_route(
+            "GET",
+            "/regions/ranking",
+            "Rank regions by a Bayesian average of their ratings",
+            get(get_region_ranking),
+        ),
+        api_route("GET", "/regions/:region/trend", "Average rating per vintage in a region", get(get_region_trend)),
+        api_route("GET", "/varieties", "Count and average rating per variety", get(get_varieties)),
+        api_route(
+            "GET",
+            "/varieties/:variety/outliers",
+            "List wines rated unusually for their variety",
+            get(get_variety_outliers),
+        ),
+        api_route("GET", "/varieties/leaders", "Highest-rated wine per variety", get(get_variety_leaders)),
+        api_route("GET", "/stats", "Catalog totals, region and variety counts in one response", get(get_stats_overview)),
+        api_route("GET", "/stats/confidence", "Mean rating with a 95% confidence interval", get(get_rating_confidence)),
+        api_route("GET", "/stats/rating-percentile/:p", "Rating at a percentile", get(get_rating_percentile)),
+        api_route(
+            "GET",
+            "/stats/price-rating-correlation",
+            "Pearson correlation between price and rating",
+            get(get_price_rating_correlation),
+        ),
+        api_route(
+            "GET",
+            "/stats/popular-searches",
+            "Most frequent search terms within a time window",
+            get(get_popular_searches),
+        ),
+        api_route("GET", "/stats/requests", "Count requests served since startup", get(get_request_stats)),
+        api_route("GET", "/stats/pool", "Database connection pool utilization", get(get_pool_stats)),
+        api_route("POST", "/graphql", "Run a GraphQL query", post(graphql_handler)),
+        api_route("GET", "/graphql/playground", "Open the GraphQL playground", get(graphql_playground)),
+    ]
+}
+
+fn admin_routes() -> Vec<ApiRoute> {
+    vec![
+        api_route("GET", "/admin/region-aliases", "List region aliases", get(list_region_aliases)),
+        api_route("POST", "/admin/region-aliases", "Create a region alias", post(create_region_alias)),
+        api_route("DELETE", "/admin/region-aliases/:alias", "Delete a region alias", delete(delete_region_alias)),
+        api_route("GET", "/admin/integrity", "Report referential integrity problems", get(check_integrity)),
+        api_route(
+            "POST",
+            "/admin/normalize-regions",
+            "Re-apply region normalization to existing wines",
+            post(backfill_normalized_regions),
+        ),
+        api_route("POST", "/admin/optimize", "Reclaim space with VACUUM", post(optimize_database)),
+        api_route("GET", "/admin/backup.sqlite", "Download a consistent copy of the database", get(backup_database)),
+    ]
+}
+
+fn uncompressed_routes() -> Vec<ApiRoute> {
+    vec![api_route("GET", "/health", "Liveness check for load balancers", get(health))]
+}
+
+async fn list_routes(
+    axum::extract::State(routes): axum::extract::State<RouteIndex>,
+) -> Json<Vec<RouteInfo>> {
+    Json(routes.0.to_vec())
+}
+
+fn create_app_with_config(pool: SqlitePool, config: &Config) -> Router {
+    create_app_with_in_flight(pool, config, InFlightGauge::default())
+}
+
+fn create_app_with_in_flight(pool: SqlitePool, config: &Config, in_flight: InFlightGauge) -> Router {
+    let config = Arc::new(config.clone());
+    let schema = Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .data(pool.clone())
+        .data(config.clone())
+        .finish();
+    let routes = api_routes(&pool);
+    let admin_routes = if config.admin_endpoints_enabled { admin_routes() } else { Vec::new() };
+    let uncompressed_routes = uncompressed_routes();
+    let index = routes
+        .iter()
+        .chain(&admin_routes)
+        .chain(&uncompressed_routes)
+        .map(ApiRoute::info)
+        .collect();
+    let state = AppState {
+        config: config.clone(),
+        reviews: ReviewLoader::new(pool.clone()),
+        wine_queries: WineQueries::new(pool.clone(), config.coalesce_queries),
+        events: broadcast::channel(WINE_EVENTS_CAPACITY).0,
+        pool,
+        webhook: config.webhook_url.clone().map(Webhook::new),
+        schema,
+        requests: RequestCounter::default(),
+        in_flight,
+        maintenance: MaintenanceLock::default(),
+        routes: RouteIndex(Arc::new(index)),
+    };
+
+    let mut router = into_router(routes);
+    if !admin_routes.is_empty() {
+        let admin = into_router(admin_routes)
+            .route_layer(middleware::from_fn_with_state(state.config.clone(), require_admin_token));
+        router = router.merge(admin);
+    }
+
+    // Routes merged after the compression layer (health checks) are never compressed.
+    let app = router
+        .layer(compression_layer(config.compression_min_bytes))
+        .merge(into_router(uncompressed_routes))
+        .layer(middleware::from_fn(require_body_content_type))
+        .layer(middleware::from_fn_with_state(state.config.clone(), apply_query_aliases))
+        .layer(middleware::from_fn_with_state(ConcurrencyLimit::new(&config), limit_concurrency))
+        .layer(middleware::map_response(add_retry_after))
+        .layer(middleware::from_fn_with_state(state.requests.clone(), count_requests))
+        .layer(middleware::from_fn_with_state(state.in_flight.clone(), track_in_flight))
+        .layer(cors_layer(&config.cors_origins))
+        .with_state(state);
+    with_request_timeout(app, config.request_timeout)
+        .layer(middleware::map_response_with_state(config.maintenance_message.clone(), add_maintenance_notice))
+}
+
+fn with_request_timeout(app: Router, timeout: Duration) -> Router {
+    app.layer(TimeoutLayer::new(timeout))
+}
+
+async fn shutdown_signal(in_flight: InFlightGauge) {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::warn!("failed to listen for ctrl-c: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                tracing::warn!("failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!(in_flight = in_flight.get(), "shutdown requested, draining in-flight requests");
+    tokio::spawn(log_draining(in_flight));
+}
+
+async fn log_draining(in_flight: InFlightGauge) {
+    let mut ticks = tokio::time::interval(DRAIN_LOG_INTERVAL);
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        let remaining = in_flight.get();
+        if remaining == 0 {
+            break;
+        }
+        tracing::info!(in_flight = remaining, "still draining requests");
+    }
+}
+
+fn parse_flag<T>(flag: &str, value: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|err: T::Err| format!("invalid value {:?} for --{}: {}", value, flag, err))
+}
+
+fn parse_query_args(args: &[String]) -> Result<(Tenant, WineFilters), String> {
+    let mut tenant = Tenant::default();
+    let mut filters = WineFilters::default();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(format!("unexpected argument {:?}", arg));
+        };
+        let (flag, value) = match flag.split_once('=') {
+            Some((flag, value)) => (flag, value.to_string()),
+            None => (
+                flag,
+                args.next().cloned().ok_or_else(|| format!("missing value for --{}", flag))?,
+            ),
+        };
+
+        match flag {
+            "q" => filters.q = Some(value),
+            "region" => filters.region = Some(value),
+            "region-exact" => filters.region_exact = Some(value),
+            "exclude-region" => filters.exclude_region = Some(value),
+            "variety" => filters.variety = Some(value),
+            "variety-exact" => filters.variety_exact = Some(value),
+            "min-rating" => filters.min_rating = Some(parse_flag(flag, &value)?),
+            "max-rating" => filters.max_rating = Some(parse_flag(flag, &value)?),
+            "has-notes" => filters.has_notes = Some(parse_flag(flag, &value)?),
+            "grade" => filters.grade = Some(parse_flag(flag, &value)?),
+            "above-average" => filters.above_average = Some(parse_flag(flag, &value)?),
+            "sort" => filters.sort = Some(value),
+            "limit" => filters.limit = Some(parse_flag(flag, &value)?),
+            "offset" => filters.offset = Some(parse_flag(flag, &value)?),
+            "tenant" if is_valid_identifier(&value) => tenant = Tenant(value),
+            "tenant" => return Err(format!("invalid value {:?} for --tenant", value)),
+            _ => return Err(format!("unknown option --{}", flag)),
+        }
+    }
+
+    Ok((tenant, filters))
+}
+
+async fn run_query(
+    pool: &SqlitePool,
+    tenant: &Tenant,
+    filters: &WineFilters,
+    config: &Config,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let page = page_bounds(filters.limit, filters.offset, config).map_err(|err| err.to_string())?;
+    let wines = fetch_filtered_wines(pool, tenant, filters, page)
+        .await
+        .map_err(|status| format!("query failed: {}", status))?;
+
+    Ok(serde_json::to_string_pretty(&wines)?)
+}
+
+fn log_subscriber<W>(
+    format: LogFormat,
+    filter: tracing_subscriber::EnvFilter,
+    writer: W,
+) -> Box<dyn tracing::Subscriber + Send + Sync>
+where
+    W: for<'writer> tracing_subscriber::fmt::MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(writer);
+    match format {
+        LogFormat::Pretty => Box::new(builder.pretty().finish()),
+        LogFormat::Json => Box::new(builder.json().finish()),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = Config::from_env()?;
+    tracing::subscriber::set_global_default(log_subscriber(
+        config.log_format,
+        tracing_subscriber::EnvFilter::from_default_env(),
+        std::io::stderr,
+    ))?;
+
+    match args.first().map(String::as_str) {
+        None | Some("serve") => {}
+        Some("query") => {
+            let (tenant, filters) = parse_query_args(&args[1..])?;
+            let pool = connect_pool(&config).await?;
+            run_migrations(&pool).await?;
+            set_source_scales(&pool, &config.source_scales).await?;
+            println!("{}", run_query(&pool, &tenant, &filters, &config).await?);
+            return Ok(());
+        }
+        Some(command) => return Err(format!("unknown command {:?} (expected serve or query)", command).into()),
+    }
+
+    let pool = connect_pool(&config).await?;
+    run_migrations(&pool).await?;
+    set_unique_wine_names(&pool, config.unique_wine_names).await?;
+    set_source_scales(&pool, &config.source_scales).await?;
+    let in_flight = InFlightGauge::default();
+    let app = create_app_with_in_flight(pool, &config, in_flight.clone());
+    
+    let listener = tokio::net::TcpListener::bind(config.bind_address()).await?;
+    println!("Wine API server running on http://{}", config.bind_address());
+    
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal(in_flight))
+        .await?;
+    tracing::info!("server stopped");
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum_test::TestServer;
+    use sqlx::SqlitePool;
+
+    const TEST_ADMIN_TOKEN: &str = "admin-secret";
+
+    fn admin_test_server(pool: SqlitePool) -> TestServer {
+        let config = Config {
+            admin_token: Some(TEST_ADMIN_TOKEN.to_string()),
+            ..Config::default()
+        };
+        let mut server = TestServer::new(create_app_with_config(pool, &config)).unwrap();
+        server.add_header(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", TEST_ADMIN_TOKEN)).unwrap(),
+        );
+        server
+    }
+
+    async fn setup_test_db() -> SqlitePool {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        
+        sqlx::query(
+            "CREATE TABLE wine_ratings (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                region TEXT,
+                variety TEXT,
+                rating REAL,
+                notes TEXT
+            )"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, region, variety, rating, notes) VALUES 
+            (1, 'Test Cabernet 2020', 'California', 'Red Wine', 92.5, 'Rich and bold with notes of cherry'),
+            (2, 'Test Chardonnay 2021', 'California', 'White Wine', 88.0, 'Crisp and clean with citrus notes'),
+            (3, 'Test Pinot Noir 2019', 'Oregon', 'Red Wine', 90.0, 'Light bodied with earthy undertones'),
+            (4, 'Bourbon Barrel Aged Red', 'Texas', 'Red Wine', 95.0, 'Aged in bourbon barrels with vanilla notes'),
+            (5, 'Test Sauvignon Blanc', 'Washington', 'White Wine', 86.5, 'Fresh and herbaceous')"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        run_migrations(&pool).await.unwrap();
+
+        pool
+    }
+
+    #[tokio::test]
+    async fn test_get_all_wines() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines").await;
+        response.assert_status_ok();
+        
+        let wines: Vec<Wine> = response.json();
+        assert_eq!(wines.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_filter_wines_by_region() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines").add_query_param("region", "California").await;
+        response.assert_status_ok();
+        
+        let wines: Vec<Wine> = response.json();
+        assert_eq!(wines.len(), 2);
+        assert!(wines.iter().all(|w| w.region.as_ref().unwrap().contains("California")));
+    }
+
+    #[tokio::test]
+    async fn test_filter_wines_by_rating() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines").add_query_param("min_rating", "90").await;
+        response.assert_status_ok();
+        
+        let wines: Vec<Wine> = response.json();
+        assert_eq!(wines.len(), 3);
+        assert!(wines.iter().all(|w| w.rating.unwrap() >= 90.0));
+    }
+
+    #[tokio::test]
+    async fn test_get_wine_ids_only() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines").add_query_param("region", "California").await;
+        let wines: Vec<Wine> = response.json();
+        let expected: Vec<i64> = wines.iter().map(|w| w.id).collect();
+
+        let response = server
+            .get("/wines")
+            .add_query_param("region", "California")
+            .add_query_param("ids_only", "true")
+            .await;
+        response.assert_status_ok();
+
+        let ids: Vec<i64> = response.json();
+        assert_eq!(ids, expected);
+    }
+
+    #[tokio::test]
+    async fn test_sort_wines_by_multiple_columns() {
+        let pool = setup_test_db().await;
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, region, variety, rating, notes) VALUES
+            (6, 'Another Pinot Noir 2019', 'Oregon', 'Red Wine', 90.0, 'Bright red fruit')"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines").add_query_param("sort", "-rating,name").await;
+        response.assert_status_ok();
+
+        let wines: Vec<Wine> = response.json();
+        let names: Vec<&str> = wines.iter().map(|w| w.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "Bourbon Barrel Aged Red",
+                "Test Cabernet 2020",
+                "Another Pinot Noir 2019",
+                "Test Pinot Noir 2019",
+                "Test Chardonnay 2021",
+                "Test Sauvignon Blanc",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sort_wines_by_unknown_column() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines").add_query_param("sort", "-rating,colour").await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_create_wine() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool.clone());
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .post("/wines")
+            .json(&serde_json::json!({
+                "name": "New Zinfandel 2022",
+                "region": "California",
+                "variety": "Red Wine",
+                "rating": 89.0,
+                "notes": "Jammy and ripe"
+            }))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+
+        let wine: Wine = response.json();
+        assert_eq!(wine.id, 6);
+        assert_eq!(wine.name, "New Zinfandel 2022");
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM wine_ratings")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 6);
+    }
+
+    #[tokio::test]
+    async fn test_prefer_return_minimal_omits_write_bodies() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+        let prefer = header::HeaderName::from_static(PREFER_HEADER);
+
+        let response = server
+            .post("/wines")
+            .add_header(prefer.clone(), HeaderValue::from_static("return=minimal"))
+            .json(&serde_json::json!({"name": "Quiet Merlot"}))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        assert!(response.as_bytes().is_empty());
+        assert_eq!(response.header(header::LOCATION), "/wines/6");
+        assert_eq!(response.header(PREFERENCE_APPLIED_HEADER), "return=minimal");
+
+        let response = server
+            .patch("/wines/6")
+            .add_header(header::IF_MATCH, HeaderValue::from_static("\"1\""))
+            .add_header(prefer.clone(), HeaderValue::from_static("handling=lenient, return=minimal"))
+            .json(&serde_json::json!({"rating": 84.0}))
+            .await;
+        response.assert_status(StatusCode::NO_CONTENT);
+        assert!(response.as_bytes().is_empty());
+
+        let response = server
+            .patch("/wines/6")
+            .add_header(header::IF_MATCH, HeaderValue::from_static("\"2\""))
+            .add_header(prefer.clone(), HeaderValue::from_static("return=representation"))
+            .json(&serde_json::json!({"rating": 85.0}))
+            .await;
+        response.assert_status_ok();
+        assert_eq!(response.json::<PatchResult>().wine.rating, Some(85.0));
+
+        let response = server
+            .patch("/wines/6")
+            .add_header(header::IF_MATCH, HeaderValue::from_static("\"3\""))
+            .add_header(prefer, HeaderValue::from_static("return=minimal"))
+            .json(&serde_json::json!({"rating": 150.0}))
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(!response.json::<ValidationErrors>().errors.is_empty());
+
+        let wine: Wine = server.get("/wines/6").await.json();
+        assert_eq!(wine.rating, Some(85.0));
+    }
+
+    #[tokio::test]
+    async fn test_rating_normalized_by_source_scale() {
+        let pool = setup_test_db().await;
+        set_source_scales(&pool, &[("Decanter".to_string(), 20.0)]).await.unwrap();
+        let server = TestServer::new(create_app(pool)).unwrap();
+
+        let response = server
+            .post("/wines")
+            .json(&serde_json::json!({"name": "Scaled Syrah", "rating": 18.0, "source": "decanter"}))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        let wine: Wine = response.json();
+        assert_eq!(wine.rating, Some(18.0));
+        assert_eq!(wine.rating_normalized, Some(90.0));
+
+        let response = server.get("/wines").add_query_param("min_rating", "90").await;
+        let wines: Vec<Wine> = response.json();
+        let names: Vec<&str> = wines.iter().map(|wine| wine.name.as_str()).collect();
+        assert_eq!(names, ["Test Cabernet 2020", "Test Pinot Noir 2019", "Bourbon Barrel Aged Red", "Scaled Syrah"]);
+        assert_eq!(wines[0].rating_normalized, wines[0].rating);
+    }
+
+    #[tokio::test]
+    async fn test_create_wine_rejects_duplicate_name_and_vintage() {
+        let pool = setup_test_db().await;
+        set_unique_wine_names(&pool, true).await.unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let wine = serde_json::json!({"name": "Estate Merlot", "vintage": 2018});
+        server.post("/wines").json(&wine).await.assert_status(StatusCode::CREATED);
+
+        let response = server.post("/wines").json(&wine).await;
+        response.assert_status(StatusCode::CONFLICT);
+        let conflict: ConflictError = response.json();
+        assert_eq!(conflict.name, "Estate Merlot");
+        assert_eq!(conflict.vintage, Some(2018));
+
+        let response = server
+            .post("/wines")
+            .json(&serde_json::json!({"name": "Estate Merlot", "vintage": 2019}))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_unique_wine_names_can_be_disabled() {
+        let pool = setup_test_db().await;
+        set_unique_wine_names(&pool, true).await.unwrap();
+        set_unique_wine_names(&pool, false).await.unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let wine = serde_json::json!({"name": "Test Cabernet 2020"});
+        server.post("/wines").json(&wine).await.assert_status(StatusCode::CREATED);
+        server.post("/wines").json(&wine).await.assert_status(StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_unique_wine_names_skipped_when_rows_collide() {
+        let pool = setup_test_db().await;
+        sqlx::query("INSERT INTO wine_ratings (name) VALUES ('Test Cabernet 2020')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        set_unique_wine_names(&pool, true).await.unwrap();
+        let server = TestServer::new(create_app(pool)).unwrap();
+
+        let wine = serde_json::json!({"name": "Test Cabernet 2020"});
+        server.post("/wines").json(&wine).await.assert_status(StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_create_wine_with_image_url() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .post("/wines")
+            .json(&serde_json::json!({"name": "Photo Merlot", "image_url": "https://img.example.com/merlot.jpg"}))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        let wine: Wine = response.json();
+
+        let fetched: Wine = server.get(&format!("/wines/{}", wine.id)).await.json();
+        assert_eq!(fetched.image_url.as_deref(), Some("https://img.example.com/merlot.jpg"));
+
+        for image_url in ["ftp://img.example.com/merlot.jpg", "not a url", "javascript:alert(1)"] {
+            let response = server
+                .post("/wines")
+                .json(&serde_json::json!({"name": "Bad Photo", "image_url": image_url}))
+                .await;
+            response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tenants_cannot_read_each_others_wines() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+        let tenant_header = header::HeaderName::from_static(TENANT_HEADER);
+        let tenant_b = HeaderValue::from_static("tenant-b");
+
+        let response = server
+            .post("/wines")
+            .add_header(tenant_header.clone(), tenant_b.clone())
+            .json(&serde_json::json!({
+                "name": "Secret Reserve",
+                "region": "Secret Valley",
+                "variety": "Secret Blend",
+                "rating": 99.0,
+                "notes": "Secret cherry vanilla",
+                "vintage": 2015,
+                "latitude": 38.5,
+                "longitude": -122.5
+            }))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        let secret: Wine = response.json();
+        server
+            .post("/tags/private/assign")
+            .add_header(tenant_header.clone(), tenant_b.clone())
+            .json(&serde_json::json!({}))
+            .await
+            .assert_status_ok();
+        let saved: SavedFilter = server
+            .post("/filters")
+            .add_header(tenant_header.clone(), tenant_b.clone())
+            .json(&serde_json::json!({"region": "Secret"}))
+            .await
+            .json();
+        server
+            .get("/wines/search")
+            .add_header(tenant_header.clone(), tenant_b.clone())
+            .add_query_param("q", "secret")
+            .await
+            .assert_status_ok();
+
+        let response = server.get("/wines").add_header(tenant_header.clone(), tenant_b.clone()).await;
+        let ids: Vec<i64> = response.json::<Vec<Wine>>().iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![secret.id]);
+
+        // Every read endpoint, as the default tenant, must behave as if tenant B's data did not exist.
+        let routes: Vec<RouteInfo> = server.get("/").await.json();
+        let streaming = ["/wines/events"];
+        for route in routes
+            .iter()
+            .filter(|r| r.method == "GET" && !r.path.starts_with("/admin") && !streaming.contains(&r.path.as_str()))
+        {
+            let path = route
+                .path
+                .replace("/filters/:id", &format!("/filters/{}", saved.id))
+                .replace(":id", &secret.id.to_string())
+                .replace(":region", "Secret%20Valley")
+                .replace(":letter", "S")
+                .replace(":tag", "private")
+                .replace(":p", "100");
+            let response = server
+                .get(&path)
+                .add_query_param("q", "cherry")
+                .add_query_param("min_lat", "30")
+                .add_query_param("max_lat", "45")
+                .add_query_param("min_lng", "-130")
+                .add_query_param("max_lng", "-110")
+                .await;
+            assert!(!response.text().to_lowercase().contains("secret"), "{} leaked: {}", path, response.text());
+        }
+        server.get(&format!("/wines/{}", secret.id)).await.assert_status_not_found();
+        server
+            .get(&format!("/filters/{}/wines", saved.id))
+            .await
+            .assert_status_not_found();
+
+        let response = server.post("/wines/by-regions").json(&["Secret Valley"]).await;
+        let grouped: HashMap<String, Vec<Wine>> = response.json();
+        assert!(grouped["Secret Valley"].is_empty());
+        let reads = [
+            ("/wines/by-flavor", serde_json::json!(["cherry", "vanilla"])),
+            ("/graphql", serde_json::json!({"query": "{ wines { name } regions { region } varieties { variety } }"})),
+            (
+                "/graphql",
+                serde_json::json!({"query": format!("{{ wine(id: {}) {{ name }} }}", secret.id)}),
+            ),
+        ];
+        for (path, body) in reads {
+            let response = server.post(path).json(&body).await;
+            response.assert_status_ok();
+            assert!(!response.text().to_lowercase().contains("secret"), "{} leaked: {}", path, response.text());
+        }
+
+        let response = server
+            .patch(&format!("/wines/{}", secret.id))
+            .json(&serde_json::json!({"rating": 1.0}))
+            .await;
+        response.assert_status_not_found();
+        let response = server
+            .get(&format!("/wines/{}", secret.id))
+            .add_header(tenant_header.clone(), tenant_b.clone())
+            .await;
+        assert_eq!(response.json::<Wine>().rating, Some(99.0));
+
+        let response = server
+            .get("/wines")
+            .add_header(tenant_header, HeaderValue::from_static("not a tenant"))
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_tenant_tokens_decide_the_tenant() {
+        let pool = setup_test_db().await;
+        let vars = HashMap::from([("TENANT_TOKENS".to_string(), "token-a=default, token-b=tenant-b".to_string())]);
+        let config = Config::from_vars(&vars).unwrap();
+        let app = create_app_with_config(pool, &config);
+        let server = TestServer::new(app).unwrap();
+
+        server.get("/wines").await.assert_status(StatusCode::UNAUTHORIZED);
+        server
+            .get("/wines")
+            .add_header(header::AUTHORIZATION, HeaderValue::from_static("Bearer wrong"))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        let response = server
+            .get("/wines")
+            .add_header(header::AUTHORIZATION, HeaderValue::from_static("Bearer token-a"))
+            .await;
+        assert_eq!(response.json::<Vec<Wine>>().len(), 5);
+
+        // The header cannot override the tenant bound to the token.
+        let response = server
+            .get("/wines")
+            .add_header(header::AUTHORIZATION, HeaderValue::from_static("Bearer token-b"))
+            .add_header(header::HeaderName::from_static(TENANT_HEADER), HeaderValue::from_static("default"))
+            .await;
+        assert!(response.json::<Vec<Wine>>().is_empty());
+
+        let vars = HashMap::from([("TENANT_TOKENS".to_string(), "token-a=bad tenant".to_string())]);
+        assert_eq!(Config::from_vars(&vars).unwrap_err().key, "TENANT_TOKENS");
+    }
+
+    #[tokio::test]
+    async fn test_route_index_lists_registered_routes() {
+        let pool = setup_test_db().await;
+        let config = Config {
+            admin_endpoints_enabled: false,
+            ..Config::default()
+        };
+        let app = create_app_with_config(pool, &config);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/").await;
+        response.assert_status_ok();
+        let routes: Vec<RouteInfo> = response.json();
+        let listed = |method: &str, path: &str| routes.iter().any(|r| r.method == method && r.path == path);
+        assert!(listed("GET", "/wines"));
+        assert!(listed("POST", "/wines"));
+        assert!(listed("GET", "/wines/search"));
+        assert!(listed("GET", "/health"));
+        assert!(!routes.iter().any(|r| r.path.starts_with("/admin")));
+        assert!(routes.iter().all(|r| !r.description.is_empty()));
+
+        let streaming = ["/wines/events"];
+        for route in routes
+            .iter()
+            .filter(|r| r.method == "GET" && !r.path.contains(':') && !streaming.contains(&r.path.as_str()))
+        {
+            let response = server.get(&route.path).await;
+            assert_ne!(response.status_code(), StatusCode::NOT_FOUND, "{}", route.path);
+            assert_ne!(response.status_code(), StatusCode::METHOD_NOT_ALLOWED, "{}", route.path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_queries_use_indexes() {
+        let pool = setup_test_db().await;
+        run_migrations(&pool).await.unwrap();
+
+        async fn plan(pool: &SqlitePool, filters: WineFilters) -> Vec<String> {
+            let mut builder = QueryBuilder::new("EXPLAIN QUERY PLAN SELECT COUNT(*) FROM wine_ratings");
+            push_filter_conditions(&mut builder, &Tenant::default(), &filters);
+            builder
+                .build()
+                .fetch_all(pool)
+                .await
+                .unwrap()
+                .iter()
+                .map(|row| row.get("detail"))
+                .collect()
+        }
+
+        let region = plan(
+            &pool,
+            WineFilters {
+                region_exact: Some("California".to_string()),
+                ..WineFilters::default()
+            },
+        )
+        .await;
+        assert!(region.iter().any(|step| step.contains("idx_wine_ratings_tenant_region_rating")), "{:?}", region);
+        assert!(!region.iter().any(|step| step.starts_with("SCAN")), "{:?}", region);
+
+        let variety = plan(
+            &pool,
+            WineFilters {
+                variety_exact: Some("Red Wine".to_string()),
+                ..WineFilters::default()
+            },
+        )
+        .await;
+        assert!(variety.iter().any(|step| step.contains("idx_wine_ratings_tenant_variety")), "{:?}", variety);
+    }
+
+    #[tokio::test]
+    async fn test_patch_wine_reports_changed_fields() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .patch("/wines/1")
+            .add_header(header::IF_MATCH, HeaderValue::from_static("1"))
+            .json(&serde_json::json!({"rating": 92.5}))
+            .await;
+        response.assert_status_ok();
+        let result: PatchResult = response.json();
+        assert!(result.changed.is_empty());
+        assert_eq!(result.wine.rating, Some(92.5));
+
+        let response = server
+            .patch("/wines/1")
+            .add_header(header::IF_MATCH, HeaderValue::from_static("2"))
+            .json(&serde_json::json!({"rating": 94.0, "notes": "Rich and bold with notes of cherry"}))
+            .await;
+        response.assert_status_ok();
+        let result: PatchResult = response.json();
+        assert_eq!(result.changed, vec!["rating"]);
+        assert_eq!(result.wine.rating, Some(94.0));
+        assert_eq!(result.wine.name, "Test Cabernet 2020");
+
+        let response = server
+            .patch("/wines/1")
+            .add_header(header::IF_MATCH, HeaderValue::from_static("3"))
+            .json(&serde_json::json!({"rating": 150.0}))
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = server
+            .patch("/wines/999")
+            .add_header(header::IF_MATCH, HeaderValue::from_static("1"))
+            .json(&serde_json::json!({"rating": 90.0}))
+            .await;
+        response.assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_updates_require_current_version() {
+        let pool = setup_test_db().await;
+        let server = TestServer::new(create_app(pool)).unwrap();
+
+        let response = server.get("/wines/1").await;
+        assert_eq!(response.header(header::ETAG), "\"1\"");
+
+        let response = server.patch("/wines/1").json(&serde_json::json!({"rating": 93.0})).await;
+        response.assert_status(StatusCode::PRECONDITION_REQUIRED);
+        let response = server
+            .patch("/wines/1")
+            .add_header(header::IF_MATCH, HeaderValue::from_static("soon"))
+            .json(&serde_json::json!({"rating": 93.0}))
+            .await;
+        response.assert_status_bad_request();
+
+        // Two clients read version 1; the first update wins and bumps the version.
+        let response = server
+            .patch("/wines/1")
+            .add_header(header::IF_MATCH, HeaderValue::from_static("\"1\""))
+            .json(&serde_json::json!({"rating": 93.0}))
+            .await;
+        response.assert_status_ok();
+        assert_eq!(response.header(header::ETAG), "\"2\"");
+        assert_eq!(response.json::<PatchResult>().wine.version, 2);
+
+        let response = server
+            .patch("/wines/1")
+            .add_header(header::IF_MATCH, HeaderValue::from_static("\"1\""))
+            .json(&serde_json::json!({"notes": "Overwritten"}))
+            .await;
+        response.assert_status(StatusCode::CONFLICT);
+        let conflict: VersionConflict = response.json();
+        assert_eq!(conflict.current_version, Some(2));
+        let wine: Wine = server.get("/wines/1").await.json();
+        assert_eq!((wine.rating, wine.version), (Some(93.0), 2));
+        assert_eq!(wine.notes.as_deref(), Some("Rich and bold with notes of cherry"));
+
+        server
+            .put("/wines/by-external/feed-7")
+            .json(&serde_json::json!({"name": "Versioned Gamay"}))
+            .await
+            .assert_status(StatusCode::CREATED);
+        let response = server
+            .put("/wines/by-external/feed-7")
+            .add_header(header::IF_MATCH, HeaderValue::from_static("0"))
+            .json(&serde_json::json!({"name": "Versioned Gamay", "rating": 87.0}))
+            .await;
+        response.assert_status(StatusCode::CONFLICT);
+        let response = server
+            .put("/wines/by-external/feed-7")
+            .add_header(header::IF_MATCH, HeaderValue::from_static("\"1\""))
+            .json(&serde_json::json!({"name": "Versioned Gamay", "rating": 87.0}))
+            .await;
+        response.assert_status_ok();
+
+        // If-Match: * accepts any current version but still requires the wine to exist.
+        let response = server
+            .patch("/wines/1")
+            .add_header(header::IF_MATCH, HeaderValue::from_static("*"))
+            .json(&serde_json::json!({"rating": 94.0}))
+            .await;
+        response.assert_status_ok();
+        assert_eq!(response.json::<PatchResult>().wine.version, 3);
+        let response = server
+            .put("/wines/by-external/feed-8")
+            .add_header(header::IF_MATCH, HeaderValue::from_static("*"))
+            .json(&serde_json::json!({"name": "Unseen Gamay"}))
+            .await;
+        response.assert_status(StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_upsert_wine_by_external_id() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool.clone());
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .put("/wines/by-external/feed-42")
+            .json(&serde_json::json!({"name": "Imported Malbec", "rating": 88.0}))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        let first: UpsertResult = response.json();
+        assert!(first.created);
+        assert_eq!(first.wine.external_id.as_deref(), Some("feed-42"));
+
+        let response = server
+            .put("/wines/by-external/feed-42")
+            .json(&serde_json::json!({"name": "Imported Malbec", "rating": 91.0, "notes": "Re-scored"}))
+            .await;
+        response.assert_status_ok();
+        let second: UpsertResult = response.json();
+        assert!(!second.created);
+        assert_eq!(second.wine.id, first.wine.id);
+        assert_eq!(second.wine.rating, Some(91.0));
+        assert_eq!(second.wine.notes.as_deref(), Some("Re-scored"));
+        assert_eq!(second.wine.version, 2);
+        assert_eq!(response.header(header::ETAG), "\"2\"");
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM wine_ratings WHERE external_id = 'feed-42'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_create_wine_reports_all_validation_errors() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .post("/wines")
+            .json(&serde_json::json!({"name": "", "rating": 150.0}))
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body: ValidationErrors = response.json();
+        let fields: Vec<&str> = body.errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["name", "rating"]);
+        assert_eq!(body.errors[1].message, "must be between 0 and 100");
+    }
+
+    #[tokio::test]
+    async fn test_create_wine_with_empty_name() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.post("/wines").json(&serde_json::json!({"name": "  "})).await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_import_wines_from_url() {
+        let files = Router::new()
+            .route(
+                "/wines.csv",
+                get(|| async { "name,region,rating,vintage,price\nCsv Grenache,Spain,89.5,2019,18.0\nCsv Syrah,,91.0,,\n" }),
+            )
+            .route(
+                "/wines.json",
+                get(|| async { r#"[{"name": "Json Malbec", "rating": 88.0}, {"name": "Csv Syrah", "rating": 91.0}]"# }),
+            )
+            .route("/bad.json", get(|| async { r#"[{"name": " ", "rating": 150.0}]"# }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, files).await.unwrap() });
+
+        let pool = setup_test_db().await;
+        set_unique_wine_names(&pool, true).await.unwrap();
+        // The file server listens on loopback, which imports refuse by default.
+        let config = Config {
+            import_allow_private_hosts: true,
+            ..Config::default()
+        };
+        let app = create_app_with_config(pool.clone(), &config);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .post("/wines/import-url")
+            .json(&serde_json::json!({"url": format!("http://{}/wines.csv", addr), "format": "csv"}))
+            .await;
+        response.assert_status_ok();
+        let summary: ImportSummary = response.json();
+        assert_eq!((summary.imported, summary.duplicates), (2, 0));
+
+        let grenache = sqlx::query_as::<_, (Option<String>, Option<i64>, Option<f64>)>(
+            "SELECT region, vintage, price FROM wine_ratings WHERE name = 'Csv Grenache'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(grenache, (Some("Spain".to_string()), Some(2019), Some(18.0)));
+
+        let response = server
+            .post("/wines/import-url")
+            .json(&serde_json::json!({"url": format!("http://{}/wines.json", addr), "format": "json"}))
+            .await;
+        let summary: ImportSummary = response.json();
+        assert_eq!((summary.imported, summary.duplicates), (1, 1));
+
+        let response = server
+            .post("/wines/import-url")
+            .json(&serde_json::json!({"url": format!("http://{}/bad.json", addr), "format": "json"}))
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        let fields: Vec<String> = response.json::<ValidationErrors>().errors.into_iter().map(|e| e.field).collect();
+        assert_eq!(fields, vec!["[0].name", "[0].rating"]);
+
+        for url in ["file:///etc/passwd", "ftp://example.com/wines.csv"] {
+            let response = server
+                .post("/wines/import-url")
+                .json(&serde_json::json!({"url": url, "format": "csv"}))
+                .await;
+            response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+
+        let response = server
+            .post("/wines/import-url")
+            .json(&serde_json::json!({"url": format!("http://{}/missing.csv", addr), "format": "csv"}))
+            .await;
+        response.assert_status(StatusCode::BAD_GATEWAY);
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM wine_ratings").fetch_one(&pool).await.unwrap();
+        assert_eq!(count, 8);
+    }
+
+    #[tokio::test]
+    async fn test_import_from_url_refuses_internal_hosts() {
+        let pool = setup_test_db().await;
+        let server = TestServer::new(create_app(pool.clone())).unwrap();
+        for url in [
+            "http://localhost/wines.csv",
+            "http://127.0.0.1:8080/wines.csv",
+            "http://169.254.169.254/latest/meta-data",
+            "http://10.0.0.5/wines.csv",
+            "http://[::1]/wines.csv",
+        ] {
+            let response = server
+                .post("/wines/import-url")
+                .json(&serde_json::json!({"url": url, "format": "csv"}))
+                .await;
+            response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(response.json::<ValidationErrors>().errors[0].field, "url");
+        }
+
+        // A redirect is not followed, even when the first host itself may be fetched.
+        let files = Router::new().route(
+            "/moved.csv",
+            get(|| async { axum::response::Redirect::temporary("http://169.254.169.254/latest/meta-data") }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, files).await.unwrap() });
+        let config = Config {
+            import_allow_private_hosts: true,
+            ..Config::default()
+        };
+        let server = TestServer::new(create_app_with_config(pool.clone(), &config)).unwrap();
+        let response = server
+            .post("/wines/import-url")
+            .json(&serde_json::json!({"url": format!("http://{}/moved.csv", addr), "format": "csv"}))
+            .await;
+        response.assert_status(StatusCode::BAD_GATEWAY);
+        assert!(response.json::<ErrorMessage>().error.contains("redirects are not followed"));
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM wine_ratings").fetch_one(&pool).await.unwrap();
+        assert_eq!(count, 5);
+    }
+
+    #[tokio::test]
+    async fn test_validate_import_reports_each_row() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool.clone());
+        let server = TestServer::new(app).unwrap();
+
+        let csv = "name,region,rating,vintage\n\
+            Good Grenache,Spain,89.5,2019\n\
+            ,Spain,150,2020\n\
+            Bad Vintage,Spain,90,last year\n\
+            Plain Syrah,,,\n";
+        let response = server
+            .post("/wines/import/validate")
+            .content_type("text/csv")
+            .bytes(csv.into())
+            .await;
+        response.assert_status_ok();
+
+        let report: ImportValidation = response.json();
+        let outcomes: Vec<(usize, bool)> = report.rows.iter().map(|row| (row.row, row.valid)).collect();
+        assert_eq!(outcomes, vec![(1, true), (2, false), (3, false), (4, true)]);
+        let fields = |row: usize| report.rows[row].errors.iter().map(|e| e.field.as_str()).collect::<Vec<_>>();
+        assert_eq!(fields(1), vec!["name", "rating"]);
+        assert_eq!(fields(2), vec!["vintage"]);
+        assert_eq!((report.summary.total, report.summary.valid, report.summary.invalid), (4, 2, 2));
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM wine_ratings").fetch_one(&pool).await.unwrap();
+        assert_eq!(count, 5);
+
+        server
+            .post("/wines/import/validate")
+            .json(&serde_json::json!([{"name": "Json Wine"}]))
+            .await
+            .assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_create_wine_fires_webhook() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<serde_json::Value>();
+        let receiver = Router::new().route(
+            "/hook",
+            post(move |Json(payload): Json<serde_json::Value>| {
+                let tx = tx.clone();
+                async move {
+                    tx.send(payload).unwrap();
+                    StatusCode::OK
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, receiver).await.unwrap() });
+
+        let pool = setup_test_db().await;
+        let config = Config {
+            webhook_url: Some(format!("http://{}/hook", addr)),
+            ..Config::default()
+        };
+        let app = create_app_with_config(pool, &config);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .post("/wines")
+            .json(&serde_json::json!({"name": "Webhook Merlot", "rating": 87.0}))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+
+        let payload = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(payload["id"], 6);
+        assert_eq!(payload["name"], "Webhook Merlot");
+        assert_eq!(payload["rating"], 87.0);
+    }
+
+    #[tokio::test]
+    async fn test_get_wine_schema() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines/schema").await;
+        response.assert_status_ok();
+
+        let fields: Vec<FieldSchema> = response.json();
+        assert_eq!(fields.len(), 16);
+
+        let rating = fields.iter().find(|f| f.name == "rating").unwrap();
+        assert_eq!(rating.field_type, "float");
+        assert!(rating.nullable);
+        assert!(rating.filterable);
+        assert!(rating.sortable);
+    }
+
+    #[tokio::test]
+    async fn test_get_duplicate_wines() {
+        let pool = setup_test_db().await;
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, region, variety, rating, notes) VALUES
+            (6, 'test cabernet 2020 ', 'Napa', 'Red Wine', 91.0, 'Another bottling')"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines/duplicates").await;
+        response.assert_status_ok();
+
+        let groups: Vec<DuplicateGroup> = response.json();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].name, "test cabernet 2020");
+        let ids: Vec<i64> = groups[0].wines.iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![1, 6]);
+
+        let response = server.get("/wines/duplicates").add_query_param("by_region", "true").await;
+        response.assert_status_ok();
+
+        let groups: Vec<DuplicateGroup> = response.json();
+        assert!(groups.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_bulk_patch_wines() {
+        let pool = setup_test_db().await;
+        let server = TestServer::new(create_app(pool.clone())).unwrap();
+
+        let response = server.patch("/wines").json(&serde_json::json!({"price": 20.0})).await;
+        response.assert_status_bad_request();
+        let response = server
+            .patch("/wines")
+            .add_query_param("region", " ")
+            .json(&serde_json::json!({"price": 20.0}))
+            .await;
+        response.assert_status_bad_request();
+
+        let response = server
+            .patch("/wines")
+            .add_query_param("variety_exact", "White Wine")
+            .json(&serde_json::json!({"price": 20.0, "source": "Cellar Notes"}))
+            .await;
+        response.assert_status_ok();
+        let result: BulkUpdate = response.json();
+        assert_eq!(result.updated, 2);
+
+        let priced: Vec<(i64, String)> =
+            sqlx::query_as("SELECT id, source FROM wine_ratings WHERE price = 20.0 ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(priced, [(2, "Cellar Notes".to_string()), (5, "Cellar Notes".to_string())]);
+
+        let response = server
+            .patch("/wines")
+            .add_query_param("variety_exact", "White Wine")
+            .json(&serde_json::json!({"price": -1.0}))
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        let response = server
+            .patch("/wines")
+            .add_query_param("variety_exact", "White Wine")
+            .json(&serde_json::json!({"name": "Same"}))
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_stats_overview_reports_failed_parts() {
+        let pool = setup_test_db().await;
+        let server = TestServer::new(create_app(pool.clone())).unwrap();
+
+        let response = server.get("/stats").await;
+        response.assert_status_ok();
+        let stats: StatsOverview = response.json();
+        assert_eq!(stats.total_wines, Some(5));
+        assert_eq!(stats.average_rating, Some(Some(90.4)));
+        assert_eq!(stats.total_reviews, Some(0));
+        assert!(stats.warnings.is_empty());
+
+        sqlx::query("DROP TABLE reviews").execute(&pool).await.unwrap();
+        let response = server.get("/stats").await;
+        response.assert_status_ok();
+        let body: serde_json::Value = response.json();
+        assert!(body.get("total_reviews").is_none());
+        assert_eq!(body["total_wines"], 5);
+        assert_eq!(body["regions"]["Oregon"], 1);
+        assert_eq!(body["varieties"]["White Wine"]["count"], 2);
+        assert_eq!(body["warnings"], serde_json::json!(["total_reviews could not be computed"]));
+    }
+
+    #[tokio::test]
+    async fn test_get_region_medians() {
+        let pool = setup_test_db().await;
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, region, rating) VALUES
+            (6, 'Budget Zinfandel', 'California', 80.0),
+            (7, 'Unrated Merlot', 'California', NULL),
+            (8, 'Hill Country Tempranillo', 'Texas', 85.0)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let server = TestServer::new(create_app(pool)).unwrap();
+
+        let response = server.get("/regions/medians").await;
+        response.assert_status_ok();
+        let medians: BTreeMap<String, RegionMedian> = response.json();
+        assert_eq!((medians["California"].median, medians["California"].count), (88.0, 3));
+        assert_eq!((medians["Texas"].median, medians["Texas"].count), (90.0, 2));
+        assert_eq!(medians["Oregon"].median, 90.0);
+        assert_eq!(medians.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_region_ranking_favors_well_sampled_regions() {
+        let pool = setup_test_db().await;
+        for id in 6..12 {
+            sqlx::query("INSERT INTO wine_ratings (id, name, region, rating) VALUES (?, ?, 'Sonoma', 96.0)")
+                .bind(id)
+                .bind(format!("Sonoma {}", id))
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        sqlx::query("INSERT INTO wine_ratings (id, name, region, rating) VALUES (12, 'Lone Napa', 'Napa', 99.0)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let server = TestServer::new(create_app(pool)).unwrap();
+
+        let response = server.get("/regions/ranking").await;
+        response.assert_status_ok();
+        let rankings: Vec<RegionRanking> = response.json();
+        let regions: Vec<&str> = rankings.iter().map(|ranking| ranking.region.as_str()).collect();
+        assert_eq!(regions[..2], ["Sonoma", "Napa"]);
+        assert_eq!((rankings[0].count, rankings[0].avg_rating), (6, 96.0));
+        assert_eq!((rankings[1].count, rankings[1].avg_rating), (1, 99.0));
+        assert!(rankings[0].score < rankings[0].avg_rating);
+        assert_eq!(rankings.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_query_param_aliases() {
+        let pool = setup_test_db().await;
+        let server = TestServer::new(create_app(pool.clone())).unwrap();
+
+        let ids = |response: axum_test::TestResponse| -> Vec<i64> {
+            response.json::<Vec<Wine>>().iter().map(|wine| wine.id).collect()
+        };
+        let canonical = ids(server.get("/wines").add_query_param("variety", "Red Wine").await);
+        let aliased = ids(server.get("/wines").add_query_param("grape", "Red Wine").await);
+        assert_eq!(canonical, [1, 3, 4]);
+        assert_eq!(aliased, canonical);
+
+        let response = server
+            .get("/wines")
+            .add_query_param("appellation", "Oregon")
+            .add_query_param("strict", "true")
+            .await;
+        assert_eq!(ids(response), [3]);
+
+        let config = Config {
+            query_aliases: vec![("cepage".to_string(), "variety_exact".to_string())],
+            ..Config::default()
+        };
+        let server = TestServer::new(create_app_with_config(pool, &config)).unwrap();
+        let response = server.get("/wines").add_query_param("cepage", "White Wine").await;
+        assert_eq!(ids(response), [2, 5]);
+        let response = server.get("/wines").add_query_param("grape", "White Wine").await;
+        assert_eq!(ids(response), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_config_query_aliases() {
+        let vars = HashMap::from([("QUERY_PARAM_ALIASES".to_string(), "cepage=variety, terroir=region".to_string())]);
+        let config = Config::from_vars(&vars).unwrap();
+        assert_eq!(config.query_aliases[1], ("terroir".to_string(), "region".to_string()));
+
+        for invalid in ["grape", "grape=colour", "region=variety"] {
+            let vars = HashMap::from([("QUERY_PARAM_ALIASES".to_string(), invalid.to_string())]);
+            assert_eq!(Config::from_vars(&vars).unwrap_err().key, "QUERY_PARAM_ALIASES");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_name_collisions() {
+        let pool = setup_test_db().await;
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, region, variety, rating) VALUES
+            (6, 'Test Pinot Noir 2019', 'Burgundy', 'Red Wine', 93.0),
+            (7, 'Test Pinot Noir 2019', 'Oregon', 'Red Wine', 88.0),
+            (8, 'Test Chardonnay 2021', NULL, 'White Wine', 85.0)"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let server = TestServer::new(create_app(pool)).unwrap();
+
+        let response = server.get("/wines/name-collisions").await;
+        response.assert_status_ok();
+        let collisions: Vec<NameCollision> = response.json();
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].name, "Test Pinot Noir 2019");
+        assert_eq!(collisions[0].regions, ["Burgundy", "Oregon"]);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool).route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                "done"
+            }),
+        );
+        let app = with_request_timeout(app, Duration::from_millis(20));
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/slow").await;
+        response.assert_status(StatusCode::REQUEST_TIMEOUT);
+
+        let response = server.get("/wines").await;
+        response.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_get_weighted_random_wine() {
+        let pool = setup_test_db().await;
+        sqlx::query("INSERT INTO wine_ratings (id, name) VALUES (6, 'Unrated Rosé')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        for _ in 0..20 {
+            let response = server.get("/wines/weighted-random").await;
+            response.assert_status_ok();
+
+            let wine: Wine = response.json();
+            assert!(wine.rating.is_some());
+        }
+    }
+
+    #[test]
+    fn test_weighted_random_prefers_higher_ratings() {
+        use rand::SeedableRng;
+
+        let wines: Vec<Wine> = [(1, 95.0), (2, 50.0), (3, 10.0)]
+            .into_iter()
+            .map(|(id, rating)| Wine {
+                id,
+                name: format!("Wine {}", id),
+                region: None,
+                variety: None,
+                rating: Some(rating),
+                notes: None,
+                vintage: None,
+                image_url: None,
+                latitude: None,
+                longitude: None,
+                price: None,
+                currency: None,
+                external_id: None,
+                version: 1,
+                source: None,
+                rating_normalized: Some(rating),
+                price_converted: None,
+            })
+            .collect();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        let mut counts = [0; 3];
+        for _ in 0..10_000 {
+            counts[pick_weighted_by_rating(&wines, &mut rng).unwrap()] += 1;
+        }
+
+        assert!(counts[0] > counts[1]);
+        assert!(counts[1] > counts[2]);
+        assert!((counts[0] as f64 / 10_000.0 - 95.0 / 155.0).abs() < 0.03);
+    }
+
+    #[tokio::test]
+    async fn test_update_ratings() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool.clone());
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .post("/wines/ratings")
+            .json(&serde_json::json!([
+                {"id": 1, "rating": 93.0},
+                {"id": 2, "rating": 89.5},
+                {"id": 99, "rating": 80.0}
+            ]))
+            .await;
+        response.assert_status_ok();
+
+        let summary: RatingUpdateSummary = response.json();
+        assert_eq!(summary.updated, 2);
+        assert_eq!(summary.not_found, 1);
+
+        let ratings: Vec<f64> = sqlx::query_scalar("SELECT rating FROM wine_ratings WHERE id IN (1, 2) ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(ratings, vec![93.0, 89.5]);
+    }
+
+    #[tokio::test]
+    async fn test_update_ratings_rejects_out_of_range() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool.clone());
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .post("/wines/ratings")
+            .json(&serde_json::json!([{"id": 1, "rating": 93.0}, {"id": 2, "rating": 150.0}]))
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+
+        let rating: f64 = sqlx::query_scalar("SELECT rating FROM wine_ratings WHERE id = 1")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rating, 92.5);
+    }
+
+    #[tokio::test]
+    async fn test_region_aliases() {
+        let pool = setup_test_db().await;
+        let server = admin_test_server(pool);
+
+        let response = server
+            .post("/admin/region-aliases")
+            .json(&serde_json::json!({"alias": "Calif.", "canonical": "California"}))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+
+        let response = server.get("/admin/region-aliases").await;
+        response.assert_status_ok();
+        let aliases: Vec<RegionAlias> = response.json();
+        assert_eq!(aliases.len(), 1);
+        assert_eq!(aliases[0].canonical, "California");
+
+        let response = server
+            .post("/wines")
+            .json(&serde_json::json!({"name": "Alias Syrah", "region": " calif. "}))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        let wine: Wine = response.json();
+        assert_eq!(wine.region.as_deref(), Some("California"));
+
+        let response = server.delete("/admin/region-aliases/Calif.").await;
+        response.assert_status(StatusCode::NO_CONTENT);
+
+        let response = server.delete("/admin/region-aliases/Calif.").await;
+        response.assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_backfill_normalized_regions() {
+        let pool = setup_test_db().await;
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, region) VALUES
+            (6, 'Lower Case', 'california'),
+            (7, 'Shouting', ' CALIFORNIA '),
+            (8, 'Abbreviated', 'Calif.'),
+            (9, 'Blank', '   ')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO region_aliases (alias, canonical) VALUES ('Calif.', 'California')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let server = admin_test_server(pool.clone());
+
+        let response = server.post("/admin/normalize-regions").await;
+        response.assert_status_ok();
+        let summary: RegionBackfillSummary = response.json();
+        assert_eq!(summary.updated, 4);
+
+        let regions: Vec<Option<String>> =
+            sqlx::query_scalar("SELECT region FROM wine_ratings WHERE id >= 6 ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            regions,
+            vec![
+                Some("California".to_string()),
+                Some("California".to_string()),
+                Some("California".to_string()),
+                None
+            ]
+        );
+
+        let summary: RegionBackfillSummary = server.post("/admin/normalize-regions").await.json();
+        assert_eq!(summary.updated, 0);
+    }
+
+    #[tokio::test]
+    async fn test_filter_wines_excluding_region() {
+        let pool = setup_test_db().await;
+        sqlx::query("INSERT INTO wine_ratings (id, name, variety, rating) VALUES (6, 'Mystery Red', 'Red Wine', 91.0)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines").add_query_param("exclude_region", "California").await;
+        response.assert_status_ok();
+
+        let wines: Vec<Wine> = response.json();
+        let ids: Vec<i64> = wines.iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![3, 4, 5, 6]);
+
+        let response = server
+            .get("/wines")
+            .add_query_param("exclude_region", "California")
+            .add_query_param("variety", "Red")
+            .add_query_param("min_rating", "91")
+            .await;
+        response.assert_status_ok();
+
+        let wines: Vec<Wine> = response.json();
+        let ids: Vec<i64> = wines.iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![4, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_get_rating_confidence() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/stats/confidence").add_query_param("variety", "Red Wine").await;
+        response.assert_status_ok();
+
+        let interval: ConfidenceInterval = response.json();
+        assert_eq!(interval.count, 3);
+        assert!((interval.mean.unwrap() - 92.5).abs() < 1e-9);
+        assert!((interval.std_dev.unwrap() - 2.5).abs() < 1e-9);
+        let margin = 4.303 * 2.5 / 3f64.sqrt();
+        assert!((interval.lower.unwrap() - (92.5 - margin)).abs() < 1e-9);
+        assert!((interval.upper.unwrap() - (92.5 + margin)).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_get_rating_confidence_single_wine() {
+        let pool = setup_test_db().await;
+        sqlx::query("INSERT INTO wine_ratings (id, name, variety, rating) VALUES (6, 'Lone Rosé', 'Rosé', 88.0)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/stats/confidence").add_query_param("variety", "Rosé").await;
+        response.assert_status_ok();
+
+        let interval: ConfidenceInterval = response.json();
+        assert_eq!(interval.count, 1);
+        assert_eq!(interval.mean, Some(88.0));
+        assert_eq!(interval.lower, None);
+        assert_eq!(interval.upper, None);
+    }
+
+    #[tokio::test]
+    async fn test_get_price_rating_correlation() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool.clone());
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/stats/price-rating-correlation").await;
+        response.assert_status_ok();
+        let correlation: PriceRatingCorrelation = response.json();
+        assert_eq!(correlation.count, 0);
+        assert_eq!(correlation.coefficient, None);
+
+        for (id, price) in [(1, 50.0), (2, 20.0), (3, 30.0), (4, 70.0)] {
+            sqlx::query("UPDATE wine_ratings SET price = ? WHERE id = ?")
+                .bind(price)
+                .bind(id)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let response = server.get("/stats/price-rating-correlation").await;
+        let correlation: PriceRatingCorrelation = response.json();
+        assert_eq!(correlation.count, 4);
+        assert!(correlation.coefficient.unwrap() > 0.9);
+    }
+
+    #[tokio::test]
+    async fn test_get_wine_by_id() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines/3").await;
+        response.assert_status_ok();
+        let wine: Wine = response.json();
+        assert_eq!(wine.name, "Test Pinot Noir 2019");
+
+        let response = server.get("/wines/99").await;
+        response.assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_export_wine() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines/2/export.json").await;
+        response.assert_status_ok();
+        assert_eq!(
+            response.header("content-disposition"),
+            "attachment; filename=\"wine-2.json\""
+        );
+        assert!(response.text().contains("\n  \"name\": \"Test Chardonnay 2021\""));
+        let wine: Wine = response.json();
+        assert_eq!(wine.id, 2);
+
+        let response = server.get("/wines/99/export.json").await;
+        response.assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_export_wines_anonymized() {
+        let pool = setup_test_db().await;
+        sqlx::query("INSERT INTO reviews (wine_id, reviewer, score, comment) VALUES (1, 'Alice', 90, 'Lovely')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let server = TestServer::new(create_app(pool)).unwrap();
+
+        let response = server.get("/wines/export").await;
+        response.assert_status_ok();
+        assert!(response.text().contains("\"notes\""));
+        assert!(response.text().contains("\"reviewer\": \"Alice\""));
+
+        let response = server.get("/wines/export").add_query_param("anonymize", "true").await;
+        response.assert_status_ok();
+        assert_eq!(response.header("content-disposition"), "attachment; filename=\"wines.json\"");
+        let wines: Vec<serde_json::Value> = response.json();
+        assert_eq!(wines.len(), 5);
+        assert!(wines.iter().all(|wine| wine.get("notes").is_none()));
+        assert_eq!(wines[0]["reviews"][0]["comment"], "Lovely");
+        assert!(wines[0]["reviews"][0].get("reviewer").is_none());
+
+        let response = server.get("/wines/export").add_query_param("format", "csv").await;
+        assert!(response.text().lines().next().unwrap().split(',').any(|column| column == "notes"));
+
+        let response = server
+            .get("/wines/export")
+            .add_query_param("format", "csv")
+            .add_query_param("anonymize", "true")
+            .await;
+        response.assert_status_ok();
+        assert_eq!(response.header("content-type"), "text/csv");
+        let csv = response.text();
+        let mut lines = csv.lines();
+        assert!(!lines.next().unwrap().split(',').any(|column| column == "notes"));
+        assert_eq!(lines.count(), 5);
+    }
+
+    #[test]
+    fn test_config_anonymized_fields() {
+        assert_eq!(Config::default().anonymized_fields, vec!["notes", "reviewer"]);
+
+        let vars = HashMap::from([("ANONYMIZE_FIELDS".to_string(), "notes, image_url, comment".to_string())]);
+        assert_eq!(Config::from_vars(&vars).unwrap().anonymized_fields, vec!["notes", "image_url", "comment"]);
+
+        let vars = HashMap::from([("ANONYMIZE_FIELDS".to_string(), "secret".to_string())]);
+        assert_eq!(Config::from_vars(&vars).unwrap_err().key, "ANONYMIZE_FIELDS");
+    }
+
+    #[tokio::test]
+    async fn test_head_wine_by_id() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.method(axum::http::Method::HEAD, "/wines/1").await;
+        response.assert_status_ok();
+        assert!(response.as_bytes().is_empty());
+
+        let response = server.method(axum::http::Method::HEAD, "/wines/99").await;
+        response.assert_status_not_found();
+    }
+
+    #[test]
+    fn test_config_from_vars() {
+        let vars: HashMap<String, String> = [
+            ("DATABASE_URL", "sqlite::memory:"),
+            ("HOST", "127.0.0.1"),
+            ("PORT", "8080"),
+            ("DATABASE_MAX_CONNECTIONS", "4"),
+            ("CORS_ORIGINS", "https://a.example, https://b.example"),
+            ("REQUEST_TIMEOUT_MS", "1500"),
+            ("ADMIN_ENDPOINTS_ENABLED", "false"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+
+        let config = Config::from_vars(&vars).unwrap();
+        assert_eq!(config.database_url, "sqlite::memory:");
+        assert_eq!(config.bind_address(), "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(config.max_connections, 4);
+        assert_eq!(config.min_connections, DEFAULT_MIN_CONNECTIONS);
+        assert_eq!(config.cors_origins, vec!["https://a.example", "https://b.example"]);
+        assert_eq!(config.webhook_url, None);
+        assert_eq!(config.request_timeout, Duration::from_millis(1500));
+        assert!(!config.admin_endpoints_enabled);
+
+        let config = Config::from_vars(&HashMap::new()).unwrap();
+        assert_eq!(config.database_url, DEFAULT_DATABASE_URL);
+        assert_eq!(config.bind_address(), "0.0.0.0:3000".parse().unwrap());
+    }
+
+    #[test]
+    fn test_config_peak_ages() {
+        let config = Config::default();
+        assert_eq!(config.peak_age(Some("red wine")), 8.0);
+        assert_eq!(config.peak_age(Some("Rosé")), FALLBACK_PEAK_AGE);
+        assert_eq!(config.peak_age(None), FALLBACK_PEAK_AGE);
+
+        let vars = HashMap::from([("PEAK_AGES".to_string(), "Red Wine=10, Sparkling=2.5".to_string())]);
+        let config = Config::from_vars(&vars).unwrap();
+        assert_eq!(config.peak_age(Some("Red Wine")), 10.0);
+        assert_eq!(config.peak_age(Some("Sparkling")), 2.5);
+        assert_eq!(config.peak_age(Some("White Wine")), FALLBACK_PEAK_AGE);
+
+        let vars = HashMap::from([("PEAK_AGES".to_string(), "Red Wine=0".to_string())]);
+        assert_eq!(Config::from_vars(&vars).unwrap_err().key, "PEAK_AGES");
+    }
+
+    #[test]
+    fn test_config_source_scales() {
+        assert!(Config::default().source_scales.is_empty());
+
+        let vars = HashMap::from([("SOURCE_SCALES".to_string(), "Decanter=20, Wine Spectator=100".to_string())]);
+        let config = Config::from_vars(&vars).unwrap();
+        assert_eq!(config.source_scales, [("Decanter".to_string(), 20.0), ("Wine Spectator".to_string(), 100.0)]);
+
+        let vars = HashMap::from([("SOURCE_SCALES".to_string(), "Decanter".to_string())]);
+        let err = Config::from_vars(&vars).unwrap_err();
+        assert_eq!(err.key, "SOURCE_SCALES");
+        assert!(err.to_string().contains("expected source=scale"));
+    }
+
+    #[test]
+    fn test_config_rejects_invalid_values() {
+        let vars = HashMap::from([("PORT".to_string(), "70000".to_string())]);
+        let err = Config::from_vars(&vars).unwrap_err();
+        assert_eq!(err.key, "PORT");
+        assert!(err.to_string().contains("70000"));
+
+        let vars = HashMap::from([
+            ("DATABASE_MAX_CONNECTIONS".to_string(), "2".to_string()),
+            ("DATABASE_MIN_CONNECTIONS".to_string(), "5".to_string()),
+        ]);
+        let err = Config::from_vars(&vars).unwrap_err();
+        assert_eq!(err.key, "DATABASE_MIN_CONNECTIONS");
+    }
+
+    #[test]
+    fn test_json_log_format() {
+        #[derive(Clone, Default)]
+        struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let vars = HashMap::from([("LOG_FORMAT".to_string(), "json".to_string())]);
+        let config = Config::from_vars(&vars).unwrap();
+        assert_eq!(config.log_format, LogFormat::Json);
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = log_subscriber(config.log_format, tracing_subscriber::EnvFilter::new("info"), move || {
+            writer.clone()
+        });
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(wine_id = 7, "wine created");
+            tracing::warn!("webhook failed");
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["fields"]["message"], "wine created");
+        assert_eq!(lines[0]["fields"]["wine_id"], 7);
+
+        let vars = HashMap::from([("LOG_FORMAT".to_string(), "xml".to_string())]);
+        assert_eq!(Config::from_vars(&vars).unwrap_err().key, "LOG_FORMAT");
+    }
+
+    #[tokio::test]
+    async fn test_connect_pool_applies_pragmas() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            database_url: format!("sqlite:{}?mode=rwc", dir.path().join("wines.db").display()),
+            statement_cache_capacity: 16,
+            ..Config::default()
+        };
+
+        let pool = connect_pool(&config).await.unwrap();
+        run_migrations(&pool).await.unwrap();
+
+        let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode").fetch_one(&pool).await.unwrap();
+        assert_eq!(journal_mode, "wal");
+        let synchronous: i64 = sqlx::query_scalar("PRAGMA synchronous").fetch_one(&pool).await.unwrap();
+        assert_eq!(synchronous, 1);
+    }
+
+    async fn setup_file_db(dir: &tempfile::TempDir, busy_timeout: Duration) -> (SqlitePool, Config) {
+        let config = Config {
+            database_url: format!("sqlite:{}?mode=rwc", dir.path().join("wines.db").display()),
+            busy_timeout,
+            ..Config::default()
+        };
+        let pool = connect_pool(&config).await.unwrap();
+        sqlx::query("CREATE TABLE wine_ratings (id INTEGER PRIMARY KEY, name TEXT NOT NULL, region TEXT, variety TEXT, rating REAL, notes TEXT)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        run_migrations(&pool).await.unwrap();
+        (pool, config)
+    }
+
+    #[tokio::test]
+    async fn test_write_waits_for_busy_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let (pool, config) = setup_file_db(&dir, Duration::from_secs(5)).await;
+        let server = TestServer::new(create_app_with_config(pool.clone(), &config)).unwrap();
+
+        let mut locker = pool.acquire().await.unwrap();
+        sqlx::query("BEGIN IMMEDIATE").execute(&mut *locker).await.unwrap();
+
+        let (response, _) = tokio::join!(
+            server.post("/wines").json(&serde_json::json!({"name": "Patient Pinot"})),
+            async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                sqlx::query("COMMIT").execute(&mut *locker).await.unwrap();
+            }
+        );
+        response.assert_status(StatusCode::CREATED);
+    }
+
+    #[derive(Debug)]
+    struct FakeDatabaseError(&'static str);
+
+    impl std::fmt::Display for FakeDatabaseError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "fake error {}", self.0)
+        }
+    }
+
+    impl std::error::Error for FakeDatabaseError {}
+
+    impl sqlx::error::DatabaseError for FakeDatabaseError {
+        fn message(&self) -> &str {
+            "fake error"
+        }
+
+        fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+            Some(self.0.into())
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> sqlx::error::ErrorKind {
+            sqlx::error::ErrorKind::Other
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors() {
+        let config = Config {
+            write_retry_backoff: Duration::from_millis(1),
+            ..Config::default()
+        };
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+
+        // SQLITE_BUSY twice, then success.
+        let result = retry_transient(&config, || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(sqlx::Error::Database(Box::new(FakeDatabaseError("5")))),
+                _ => Ok("written"),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), "written");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // A constraint failure is not retried.
+        attempts.store(0, Ordering::SeqCst);
+        let result: Result<(), _> = retry_transient(&config, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(sqlx::Error::Database(Box::new(FakeDatabaseError("2067"))))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        // SQLITE_LOCKED keeps failing: one attempt plus the configured retries.
+        attempts.store(0, Ordering::SeqCst);
+        let result: Result<(), _> = retry_transient(&config, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(sqlx::Error::Database(Box::new(FakeDatabaseError("6"))))
+        })
+        .await;
+        assert!(is_transient(&result.unwrap_err()));
+        assert_eq!(attempts.load(Ordering::SeqCst), DEFAULT_WRITE_RETRIES as usize + 1);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_notice_header() {
+        let pool = setup_test_db().await;
+        let vars = HashMap::from([("MAINTENANCE_MESSAGE".to_string(), "Read-only from 02:00 UTC".to_string())]);
+        let config = Config::from_vars(&vars).unwrap();
+        let server = TestServer::new(create_app_with_config(pool.clone(), &config)).unwrap();
+
+        for path in ["/wines", "/health", "/wines/42"] {
+            let response = server.get(path).await;
+            assert_eq!(response.header(MAINTENANCE_NOTICE_HEADER), "Read-only from 02:00 UTC");
+        }
+        server.get("/wines").await.assert_status_ok();
+
+        // An emptied variable clears the notice.
+        let vars = HashMap::from([("MAINTENANCE_MESSAGE".to_string(), " ".to_string())]);
+        let config = Config::from_vars(&vars).unwrap();
+        let server = TestServer::new(create_app_with_config(pool, &config)).unwrap();
+        let response = server.get("/wines").await;
+        assert!(response.maybe_header(MAINTENANCE_NOTICE_HEADER).is_none());
+
+        let vars = HashMap::from([("MAINTENANCE_MESSAGE".to_string(), "line\nbreak".to_string())]);
+        assert_eq!(Config::from_vars(&vars).unwrap_err().key, "MAINTENANCE_MESSAGE");
+    }
+
+    #[tokio::test]
+    async fn test_rating_timeline_is_chronological() {
+        let pool = setup_test_db().await;
+        for (score, created_at) in [(91.0, "2024-03-01 10:00:00"), (85.0, "2023-11-20 08:30:00"), (88.0, "2024-01-15 19:45:00")] {
+            sqlx::query("INSERT INTO reviews (wine_id, score, created_at) VALUES (2, ?, ?)")
+                .bind(score)
+                .bind(created_at)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let server = TestServer::new(create_app(pool)).unwrap();
+
+        let response = server.get("/wines/2/rating-timeline").await;
+        response.assert_status_ok();
+        let points: Vec<RatingPoint> = response.json();
+        let scores: Vec<f64> = points.iter().map(|point| point.score).collect();
+        assert_eq!(scores, vec![85.0, 88.0, 91.0]);
+        assert_eq!(points[0].created_at, "2023-11-20 08:30:00");
+
+        let points: Vec<RatingPoint> = server.get("/wines/1/rating-timeline").await.json();
+        assert!(points.is_empty());
+        server.get("/wines/42/rating-timeline").await.assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_search_review_comments() {
+        let pool = setup_test_db().await;
+        let server = TestServer::new(create_app(pool.clone())).unwrap();
+
+        let long_comment = "Opens shy and closed, then the cellar air lifts it to a long crème brûlée finish";
+        for (id, comment) in [(3, long_comment), (3, "More creme brulee please"), (1, "Cassis and cedar")] {
+            let response = server
+                .post(&format!("/wines/{}/reviews", id))
+                .json(&serde_json::json!({"reviewer": "Tester", "score": 90, "comment": comment}))
+                .await;
+            response.assert_status(StatusCode::CREATED);
+        }
+        server
+            .post("/wines/42/reviews")
+            .json(&serde_json::json!({"comment": "Ghost"}))
+            .await
+            .assert_status_not_found();
+        for score in [-1.0, 100.5] {
+            let response = server
+                .post("/wines/3/reviews")
+                .json(&serde_json::json!({"score": score, "comment": "creme brulee, off the scale"}))
+                .await;
+            response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(response.json::<ValidationErrors>().errors[0].field, "score");
+        }
+
+        let response = server.get("/reviews/search").add_query_param("q", "Creme Brulee").await;
+        response.assert_status_ok();
+        let found: Vec<ReviewMatch> = response.json();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].wine.name, "Test Pinot Noir 2019");
+        assert_eq!(found[0].matching_reviews, 2);
+        assert_eq!(found[0].snippet, "…then the cellar air lifts it to a long crème brûlée finish");
+
+        // Reviews written before comment_search existed are picked up by the backfill.
+        sqlx::query("INSERT INTO reviews (wine_id, reviewer, score, comment) VALUES (2, 'Legacy', 88, 'Crème brûlée notes')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let found: Vec<ReviewMatch> = server.get("/reviews/search").add_query_param("q", "creme brulee").await.json();
+        assert_eq!(found.len(), 1);
+        backfill_search_text(&pool).await.unwrap();
+        let found: Vec<ReviewMatch> = server.get("/reviews/search").add_query_param("q", "creme brulee").await.json();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[1].snippet, "Crème brûlée notes");
+
+        let response = server.get("/reviews/search").add_query_param("q", "merlot").await;
+        assert!(response.json::<Vec<ReviewMatch>>().is_empty());
+        server.get("/reviews/search").add_query_param("q", " ").await.assert_status_bad_request();
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit_overflow() {
+        let dir = tempfile::tempdir().unwrap();
+        let (pool, config) = setup_file_db(&dir, Duration::from_secs(5)).await;
+
+        for (overflow, expected) in [
+            (ConcurrencyOverflow::Reject, StatusCode::SERVICE_UNAVAILABLE),
+            (ConcurrencyOverflow::Queue, StatusCode::OK),
+        ] {
+            let config = Config {
+                max_concurrent_requests: 1,
+                concurrency_overflow: overflow,
+                ..config.clone()
+            };
+            // Two clients of one app, so both requests share the same limit.
+            let app = create_app_with_config(pool.clone(), &config);
+            let writer = TestServer::new(app.clone()).unwrap();
+            let reader = TestServer::new(app).unwrap();
+
+            // The write holds the only permit while it waits for the locked database.
+            let mut locker = pool.acquire().await.unwrap();
+            sqlx::query("BEGIN IMMEDIATE").execute(&mut *locker).await.unwrap();
+            let (write, read, _) = tokio::join!(
+                writer.post("/wines").json(&serde_json::json!({"name": "Crowded Cinsault"})),
+                async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    reader.get("/health").await
+                },
+                async {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    sqlx::query("COMMIT").execute(&mut *locker).await.unwrap();
+                }
+            );
+            write.assert_status(StatusCode::CREATED);
+            read.assert_status(expected);
+            if expected == StatusCode::SERVICE_UNAVAILABLE {
+                assert_eq!(read.header(header::RETRY_AFTER), RETRY_AFTER_SECONDS.to_string());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_locked_database_returns_503_with_retry_hint() {
+        let dir = tempfile::tempdir().unwrap();
+        let (pool, config) = setup_file_db(&dir, Duration::ZERO).await;
+        let server = TestServer::new(create_app_with_config(pool.clone(), &config)).unwrap();
+
+        let mut locker = pool.acquire().await.unwrap();
+        sqlx::query("BEGIN IMMEDIATE").execute(&mut *locker).await.unwrap();
+
+        let response = server.post("/wines").json(&serde_json::json!({"name": "Impatient Pinot"})).await;
+        response.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.header(header::RETRY_AFTER), RETRY_AFTER_SECONDS.to_string());
+
+        sqlx::query("ROLLBACK").execute(&mut *locker).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_admin_endpoints_disabled() {
+        let pool = setup_test_db().await;
+        let config = Config {
+            admin_endpoints_enabled: false,
+            ..Config::default()
+        };
+        let app = create_app_with_config(pool, &config);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/admin/region-aliases").await;
+        response.assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_dataset_version_changes_on_write() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let before: DatasetVersion = server.get("/version").await.json();
+        let response = server.get("/wines").await;
+        assert_eq!(response.header(DATASET_VERSION_HEADER), before.version.to_string());
+
+        server
+            .post("/wines")
+            .json(&serde_json::json!({"name": "Fresh Release"}))
+            .await
+            .assert_status(StatusCode::CREATED);
+
+        let after: DatasetVersion = server.get("/version").await.json();
+        assert!(after.version > before.version);
+        let response = server.get("/wines").await;
+        assert_eq!(response.header(DATASET_VERSION_HEADER), after.version.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_compression_skips_small_and_exempt_responses() {
+        let gzip = || HeaderValue::from_static("gzip");
+        let pool = setup_test_db().await;
+        let config = Config {
+            compression_min_bytes: 64,
+            ..Config::default()
+        };
+        let server = TestServer::new(create_app_with_config(pool.clone(), &config)).unwrap();
+
+        let response = server.get("/version").add_header(header::ACCEPT_ENCODING, gzip()).await;
+        response.assert_status_ok();
+        assert!(response.maybe_header(header::CONTENT_ENCODING).is_none());
+
+        let response = server.get("/wines").add_header(header::ACCEPT_ENCODING, gzip()).await;
+        response.assert_status_ok();
+        assert_eq!(response.header(header::CONTENT_ENCODING), "gzip");
+
+        let config = Config {
+            compression_min_bytes: 0,
+            ..Config::default()
+        };
+        let server = TestServer::new(create_app_with_config(pool, &config)).unwrap();
+
+        let response = server.get("/health").add_header(header::ACCEPT_ENCODING, gzip()).await;
+        response.assert_status_ok();
+        assert!(response.maybe_header(header::CONTENT_ENCODING).is_none());
+
+        let response = server.get("/version").add_header(header::ACCEPT_ENCODING, gzip()).await;
+        assert_eq!(response.header(header::CONTENT_ENCODING), "gzip");
+    }
+
+    #[tokio::test]
+    async fn test_optimize_database() {
+        let pool = setup_test_db().await;
+        let server = admin_test_server(pool);
+
+        let response = server.post("/admin/optimize").await;
+        response.assert_status_ok();
+        let report: OptimizeReport = response.json();
+        assert!(report.freed_bytes >= 0);
+    }
+
+    #[tokio::test]
+    async fn test_backup_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let (pool, config) = setup_file_db(&dir, Duration::from_secs(5)).await;
+        sqlx::query("INSERT INTO wine_ratings (name, rating) VALUES ('Backed Up Barolo', 94.0)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let config = Config {
+            admin_token: Some("secret".to_string()),
+            ..config
+        };
+        let app = create_app_with_config(pool, &config);
+        let server = TestServer::new(app).unwrap();
+
+        server.get("/admin/backup.sqlite").await.assert_status(StatusCode::UNAUTHORIZED);
+
+        let response = server
+            .get("/admin/backup.sqlite")
+            .add_header(header::AUTHORIZATION, HeaderValue::from_static("Bearer secret"))
+            .await;
+        response.assert_status_ok();
+        assert_eq!(response.header(header::CONTENT_TYPE), "application/vnd.sqlite3");
+        let body = response.as_bytes();
+        assert!(body.starts_with(b"SQLite format 3\0"));
+
+        let path = dir.path().join("restored.sqlite");
+        std::fs::write(&path, body).unwrap();
+        let restored = SqlitePool::connect(&format!("sqlite:{}", path.display())).await.unwrap();
+        let name: String = sqlx::query_scalar("SELECT name FROM wine_ratings").fetch_one(&restored).await.unwrap();
+        assert_eq!(name, "Backed Up Barolo");
+    }
+
+    #[tokio::test]
+    async fn test_backup_unavailable_for_in_memory_database() {
+        let server = admin_test_server(setup_test_db().await);
+
+        let response = server.get("/admin/backup.sqlite").await;
+        response.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_optimize_database_rejects_concurrent_runs() {
+        let pool = setup_test_db().await;
+        let maintenance = MaintenanceLock::default();
+        let _guard = maintenance.0.lock().await;
+
+        let result = optimize_database(axum::extract::State(pool), axum::extract::State(maintenance.clone())).await;
+        assert_eq!(result.unwrap_err(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_admin_endpoints_refused_without_configured_token() {
+        let pool = setup_test_db().await;
+        let server = TestServer::new(create_app(pool)).unwrap();
+
+        server.get("/admin/backup.sqlite").await.assert_status(StatusCode::UNAUTHORIZED);
+        server.post("/admin/optimize").await.assert_status(StatusCode::UNAUTHORIZED);
+        // No bearer value can stand in for a token that was never configured.
+        server
+            .get("/admin/backup.sqlite")
+            .add_header(header::AUTHORIZATION, HeaderValue::from_static("Bearer "))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_admin_token_required_when_configured() {
+        let pool = setup_test_db().await;
+        let config = Config {
+            admin_token: Some("secret".to_string()),
+            ..Config::default()
+        };
+        let app = create_app_with_config(pool, &config);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.post("/admin/optimize").await;
+        response.assert_status(StatusCode::UNAUTHORIZED);
+
+        let response = server
+            .post("/admin/optimize")
+            .add_header(header::AUTHORIZATION, HeaderValue::from_static("Bearer wrong"))
+            .await;
+        response.assert_status(StatusCode::UNAUTHORIZED);
+
+        let response = server
+            .post("/admin/optimize")
+            .add_header(header::AUTHORIZATION, HeaderValue::from_static("Bearer secret"))
+            .await;
+        response.assert_status_ok();
+
+        let response = server.get("/wines").await;
+        response.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_get_incomplete_wines() {
+        let pool = setup_test_db().await;
+        sqlx::query("INSERT INTO wine_ratings (id, name, region, rating) VALUES (6, 'Mystery Blend', 'Oregon', 85.0)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO wine_ratings (id, name, variety) VALUES (7, 'Unknown Origin', 'Red Wine')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines/incomplete").await;
+        response.assert_status_ok();
+
+        let wines: Vec<IncompleteWine> = response.json();
+        assert_eq!(wines.len(), 2);
+        assert_eq!(wines[0].wine.id, 6);
+        assert_eq!(wines[0].missing, vec!["variety"]);
+        assert_eq!(wines[1].missing, vec!["region", "rating"]);
+
+        let response = server.get("/wines/incomplete").add_query_param("field", "variety").await;
+        response.assert_status_ok();
+
+        let wines: Vec<IncompleteWine> = response.json();
+        assert_eq!(wines.len(), 1);
+        assert_eq!(wines[0].wine.name, "Mystery Blend");
+
+        let response = server.get("/wines/incomplete").add_query_param("field", "notes").await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_graphql_wines_query() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .post("/graphql")
+            .json(&serde_json::json!({
+                "query": "{ wines(filter: { region: \"California\" }, limit: 5) { id name } }"
+            }))
+            .await;
+        response.assert_status_ok();
+
+        let body: serde_json::Value = response.json();
+        assert!(body.get("errors").is_none());
+        assert_eq!(
+            body["data"]["wines"],
+            serde_json::json!([
+                {"id": 1, "name": "Test Cabernet 2020"},
+                {"id": 2, "name": "Test Chardonnay 2021"}
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_graphql_wine_and_aggregates() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .post("/graphql")
+            .json(&serde_json::json!({
+                "query": "{ wine(id: 4) { name rating } regions { region count } varieties { variety count } }"
+            }))
+            .await;
+        response.assert_status_ok();
+
+        let body: serde_json::Value = response.json();
+        assert_eq!(body["data"]["wine"]["name"], "Bourbon Barrel Aged Red");
+        assert_eq!(body["data"]["regions"].as_array().unwrap().len(), 4);
+        assert_eq!(body["data"]["varieties"].as_array().unwrap().len(), 2);
+
+        let response = server.get("/graphql/playground").await;
+        response.assert_status_ok();
+        assert!(response.text().contains("GraphQL Playground"));
+    }
+
+    async fn insert_review(pool: &SqlitePool, wine_id: i64, score: f64, comment: &str) {
+        sqlx::query("INSERT INTO reviews (wine_id, reviewer, score, comment) VALUES (?, 'Tester', ?, ?)")
+            .bind(wine_id)
+            .bind(score)
+            .bind(comment)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_review_loader_uses_single_query() {
+        let pool = setup_test_db().await;
+        insert_review(&pool, 1, 93.0, "Great structure").await;
+        insert_review(&pool, 1, 91.0, "Needs time").await;
+        insert_review(&pool, 3, 89.0, "Earthy").await;
+        insert_review(&pool, 4, 96.0, "Not in the list").await;
+
+        let loader = ReviewLoader::new(pool);
+        let reviews = loader.load(&[1, 2, 3]).await.unwrap();
+
+        assert_eq!(loader.query_count(), 1);
+        assert_eq!(reviews[&1].len(), 2);
+        assert!(!reviews.contains_key(&2));
+        assert_eq!(reviews[&3][0].comment.as_deref(), Some("Earthy"));
+        assert!(!reviews.contains_key(&4));
+    }
+
+    #[tokio::test]
+    async fn test_single_flight_shares_concurrent_work() {
+        let flights = SingleFlight::<u64>::default();
+        let runs = Arc::new(AtomicU64::new(0));
+        let release = Arc::new(tokio::sync::Notify::new());
+
+        let mut requests = tokio::task::JoinSet::new();
+        for _ in 0..20 {
+            let (flights, runs, release) = (flights.clone(), runs.clone(), release.clone());
+            requests.spawn(async move {
+                flights
+                    .run("wines".to_string(), async move {
+                        release.notified().await;
+                        runs.fetch_add(1, Ordering::SeqCst) + 1
+                    })
+                    .await
+            });
+        }
+        // Let every request reach the in-flight entry before the first one is allowed to finish.
+        for _ in 0..5 {
+            tokio::task::yield_now().await;
+        }
+        release.notify_one();
+
+        let mut results = Vec::new();
+        while let Some(result) = requests.join_next().await {
+            results.push(result.unwrap());
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(results, vec![1; 20]);
+
+        let runs_again = runs.clone();
+        let result = flights
+            .run("wines".to_string(), async move { runs_again.fetch_add(1, Ordering::SeqCst) + 1 })
+            .await;
+        assert_eq!(result, 2);
+    }
+
+    #[tokio::test]
+    async fn test_wine_queries_fetch_page() {
+        let pool = setup_test_db().await;
+        let filters = WineFilters {
+            variety: Some("Red Wine".to_string()),
+            ..WineFilters::default()
+        };
+        let tenant = Tenant::default();
+
+        for enabled in [true, false] {
+            let wine_queries = WineQueries::new(pool.clone(), enabled);
+            let (first, second) = tokio::join!(
+                wine_queries.fetch(&tenant, &filters, (Some(2), 0)),
+                wine_queries.fetch(&tenant, &filters, (Some(2), 0))
+            );
+            let (total, wines) = first.unwrap();
+            assert_eq!(total, 3);
+            assert_eq!(wines.iter().map(|w| w.id).collect::<Vec<_>>(), vec![1, 3]);
+            assert_eq!(second.unwrap().1.len(), 2);
+            if !enabled {
+                assert_eq!(wine_queries.query_count(), 2);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_wines_with_reviews() {
+        let pool = setup_test_db().await;
+        insert_review(&pool, 1, 93.0, "Great structure").await;
+        insert_review(&pool, 2, 87.0, "Zesty").await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .get("/wines")
+            .add_query_param("region", "California")
+            .add_query_param("include_reviews", "true")
+            .await;
+        response.assert_status_ok();
+
+        let wines: Vec<WineWithReviews> = response.json();
+        assert_eq!(wines.len(), 2);
+        assert_eq!(wines[0].wine.id, 1);
+        assert_eq!(wines[0].reviews.len(), 1);
+        assert_eq!(wines[1].reviews[0].comment.as_deref(), Some("Zesty"));
+    }
+
+    #[tokio::test]
+    async fn test_default_sort() {
+        let pool = setup_test_db().await;
+        let config = Config {
+            default_sort: Some("-rating".to_string()),
+            ..Config::default()
+        };
+        let app = create_app_with_config(pool, &config);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines").await;
+        response.assert_status_ok();
+        let wines: Vec<Wine> = response.json();
+        let ratings: Vec<f64> = wines.iter().map(|w| w.rating.unwrap()).collect();
+        assert_eq!(ratings, vec![95.0, 92.5, 90.0, 88.0, 86.5]);
+
+        let response = server.get("/wines").add_query_param("sort", "name").await;
+        response.assert_status_ok();
+        let wines: Vec<Wine> = response.json();
+        assert_eq!(wines[0].name, "Bourbon Barrel Aged Red");
+        assert_eq!(wines[4].name, "Test Sauvignon Blanc");
+    }
+
+    #[test]
+    fn test_config_rejects_invalid_default_sort() {
+        let vars = HashMap::from([("DEFAULT_SORT".to_string(), "-price".to_string())]);
+        let err = Config::from_vars(&vars).unwrap_err();
+        assert_eq!(err.key, "DEFAULT_SORT");
+    }
+
+    #[tokio::test]
+    async fn test_get_rating_percentile() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/stats/rating-percentile/50").await;
+        response.assert_status_ok();
+        let result: RatingPercentile = response.json();
+        assert_eq!(result.rating, 90.0);
+        assert_eq!(result.count, 5);
+
+        let response = server.get("/stats/rating-percentile/100").await;
+        let result: RatingPercentile = response.json();
+        assert_eq!(result.rating, 95.0);
+
+        let response = server.get("/stats/rating-percentile/87.5").await;
+        let result: RatingPercentile = response.json();
+        assert!((result.rating - 93.75).abs() < 1e-9);
+
+        let response = server.get("/stats/rating-percentile/101").await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_sort_places_nulls_last_by_default() {
+        let pool = setup_test_db().await;
+        sqlx::query("INSERT INTO wine_ratings (id, name, region) VALUES (6, 'Unrated Rosé', 'Oregon')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        for sort in ["rating", "-rating"] {
+            let response = server.get("/wines").add_query_param("sort", sort).await;
+            response.assert_status_ok();
+            let wines: Vec<Wine> = response.json();
+            assert_eq!(wines.len(), 6);
+            assert_eq!(wines[5].id, 6);
+        }
+
+        let response = server
+            .get("/wines")
+            .add_query_param("sort", "-rating")
+            .add_query_param("nulls", "first")
+            .await;
+        response.assert_status_ok();
+        let wines: Vec<Wine> = response.json();
+        assert_eq!(wines[0].id, 6);
+        assert_eq!(wines[1].rating, Some(95.0));
+
+        let response = server
+            .get("/wines")
+            .add_query_param("sort", "rating")
+            .add_query_param("nulls", "middle")
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_get_wines_geojson() {
+        let pool = setup_test_db().await;
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, region, rating, latitude, longitude) VALUES
+            (6, 'Napa Cabernet', 'California', 93.0, 38.5, -122.3),
+            (7, 'Barossa Shiraz', 'South Australia', NULL, -34.5, 138.9),
+            (8, 'Half Placed', NULL, NULL, 10.0, NULL)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines/geojson").await;
+        response.assert_status_ok();
+        assert_eq!(response.header(header::CONTENT_TYPE), "application/geo+json");
+
+        let collection: serde_json::Value = serde_json::from_str(&response.text()).unwrap();
+        assert_eq!(collection["type"], "FeatureCollection");
+        let features = collection["features"].as_array().unwrap();
+        assert_eq!(features.len(), 2);
+        assert!(features.iter().all(|f| f["type"] == "Feature" && f["geometry"]["type"] == "Point"));
+        assert_eq!(features[0]["geometry"]["coordinates"], serde_json::json!([-122.3, 38.5]));
+        assert_eq!(
+            features[0]["properties"],
+            serde_json::json!({"name": "Napa Cabernet", "region": "California", "rating": 93.0})
+        );
+        assert_eq!(features[1]["properties"]["rating"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_get_wines_in_bbox() {
+        let pool = setup_test_db().await;
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, latitude, longitude) VALUES
+            (6, 'Napa Cabernet', 38.5, -122.3),
+            (7, 'Barossa Shiraz', -34.5, 138.9)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .get("/wines/bbox")
+            .add_query_param("min_lat", "30")
+            .add_query_param("max_lat", "45")
+            .add_query_param("min_lng", "-130")
+            .add_query_param("max_lng", "-110")
+            .await;
+        response.assert_status_ok();
+        let wines: Vec<Wine> = response.json();
+        let ids: Vec<i64> = wines.iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![6]);
+
+        let response = server
+            .get("/wines/bbox")
+            .add_query_param("min_lat", "45")
+            .add_query_param("max_lat", "30")
+            .add_query_param("min_lng", "-130")
+            .add_query_param("max_lng", "-110")
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_get_wines_grouped_alpha() {
+        let pool = setup_test_db().await;
+        sqlx::query("INSERT INTO wine_ratings (id, name) VALUES (6, '1865 Reserva'), (7, 'élan Blanc')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines/grouped-alpha").await;
+        response.assert_status_ok();
+        let grouped: BTreeMap<String, Vec<Wine>> = response.json();
+
+        let names = |letter: &str| -> Vec<String> { grouped[letter].iter().map(|w| w.name.clone()).collect() };
+        assert_eq!(names("B"), vec!["Bourbon Barrel Aged Red"]);
+        assert_eq!(
+            names("T"),
+            vec![
+                "Test Cabernet 2020",
+                "Test Chardonnay 2021",
+                "Test Pinot Noir 2019",
+                "Test Sauvignon Blanc"
+            ]
+        );
+        assert_eq!(names("#"), vec!["1865 Reserva"]);
+        assert_eq!(names("E"), vec!["élan Blanc"]);
+        assert_eq!(grouped.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_recommendations_follow_favorites() {
+        let pool = setup_test_db().await;
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, region, variety, rating) VALUES
+            (6, 'Napa Zinfandel', 'California', 'Red Wine', 91.0),
+            (7, 'Cheap California Red', 'California', 'Red Wine', 70.0)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+        let user = header::HeaderName::from_static(USER_HEADER);
+
+        server.get("/me/recommendations").await.assert_status(StatusCode::UNAUTHORIZED);
+        server
+            .put("/me/favorites/99")
+            .add_header(user.clone(), header::HeaderValue::from_static("alice"))
+            .await
+            .assert_status_not_found();
+
+        for _ in 0..2 {
+            server
+                .put("/me/favorites/1")
+                .add_header(user.clone(), header::HeaderValue::from_static("alice"))
+                .await
+                .assert_status(StatusCode::NO_CONTENT);
+        }
+        let favorites: Vec<Wine> = server
+            .get("/me/favorites")
+            .add_header(user.clone(), header::HeaderValue::from_static("alice"))
+            .await
+            .json();
+        assert_eq!(favorites.iter().map(|w| w.id).collect::<Vec<_>>(), vec![1]);
+
+        let response = server
+            .get("/me/recommendations")
+            .add_header(user.clone(), header::HeaderValue::from_static("alice"))
+            .await;
+        response.assert_status_ok();
+        let recommendations: Vec<Recommendation> = response.json();
+        let ids: Vec<i64> = recommendations.iter().map(|r| r.wine.id).collect();
+        assert_eq!(ids, vec![6, 4, 3, 2]);
+        assert_eq!(recommendations[0].affinity, 2);
+
+        let response = server
+            .get("/me/recommendations")
+            .add_header(user.clone(), header::HeaderValue::from_static("bob"))
+            .await;
+        assert!(response.json::<Vec<Recommendation>>().is_empty());
+
+        server
+            .delete("/me/favorites/1")
+            .add_header(user.clone(), header::HeaderValue::from_static("alice"))
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        server
+            .delete("/me/favorites/1")
+            .add_header(user, header::HeaderValue::from_static("alice"))
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_assign_tag_by_filter() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .post("/tags/west-coast/assign")
+            .json(&serde_json::json!({"region": "California"}))
+            .await;
+        response.assert_status_ok();
+        let assignment: TagAssignment = response.json();
+        assert_eq!(assignment.tagged, 2);
+
+        let wines: Vec<Wine> = server.get("/tags/west-coast/wines").await.json();
+        let ids: Vec<i64> = wines.iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+
+        let assignment: TagAssignment = server
+            .post("/tags/west-coast/assign")
+            .json(&serde_json::json!({"region": "California"}))
+            .await
+            .json();
+        assert_eq!(assignment.tagged, 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_wines_by_regions() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .post("/wines/by-regions")
+            .json(&serde_json::json!(["California", "Oregon", "Atlantis"]))
+            .await;
+        response.assert_status_ok();
+
+        let grouped: HashMap<String, Vec<Wine>> = response.json();
+        assert_eq!(grouped.len(), 3);
+        let california: Vec<i64> = grouped["California"].iter().map(|w| w.id).collect();
+        assert_eq!(california, vec![1, 2]);
+        assert_eq!(grouped["Oregon"].len(), 1);
+        assert_eq!(grouped["Oregon"][0].name, "Test Pinot Noir 2019");
+        assert!(grouped["Atlantis"].is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_wine_events_stream() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = reqwest::Client::new();
+        let mut events = client
+            .get(format!("http://{}/wines/events", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(events.headers()["content-type"], "text/event-stream");
+
+        let response = client
+            .post(format!("http://{}/wines", addr))
+            .json(&serde_json::json!({"name": "Streamed Grenache", "rating": 90.0}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let mut received = String::new();
+        while !received.contains("\n\n") {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), events.chunk())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+
+        assert!(received.contains("event: wine_created"));
+        let data = received
+            .lines()
+            .find_map(|line| line.strip_prefix("data: "))
+            .unwrap();
+        let wine: Wine = serde_json::from_str(data).unwrap();
+        assert_eq!(wine.id, 6);
+        assert_eq!(wine.name, "Streamed Grenache");
+    }
+
+    #[tokio::test]
+    async fn test_strict_mode_rejects_unknown_params() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines").add_query_param("regon", "x").await;
+        response.assert_status_ok();
+
+        let response = server
+            .get("/wines")
+            .add_query_param("regon", "x")
+            .add_query_param("strict", "true")
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+        let error: UnknownParamsError = response.json();
+        assert_eq!(error.unknown, vec!["regon"]);
+        assert!(error.error.contains("regon"));
+
+        let response = server
+            .get("/wines")
+            .add_query_param("region", "California")
+            .add_query_param("strict", "true")
+            .await;
+        response.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_strict_mode_from_config() {
+        let pool = setup_test_db().await;
+        let config = Config {
+            strict_query_params: true,
+            ..Config::default()
+        };
+        let app = create_app_with_config(pool, &config);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines").add_query_param("regon", "x").await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+
+        let response = server
+            .get("/wines")
+            .add_query_param("regon", "x")
+            .add_query_param("strict", "false")
+            .await;
+        response.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_get_variety_outliers() {
+        let pool = setup_test_db().await;
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, variety, rating) VALUES
+            (6, 'Steady Merlot', 'Red Wine', 91.0),
+            (7, 'Steady Syrah', 'Red Wine', 93.0),
+            (8, 'Steady Malbec', 'Red Wine', 89.0),
+            (9, 'Typo Zinfandel', 'Red Wine', 20.0),
+            (10, 'Lone Rosé', 'Rosé', 40.0)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let server = TestServer::new(create_app(pool)).unwrap();
+
+        let response = server.get("/varieties/Red Wine/outliers").await;
+        response.assert_status_ok();
+        let report: VarietyOutliers = response.json();
+        assert_eq!(report.count, 7);
+        assert_eq!(report.mean, 81.5);
+        let ids: Vec<i64> = report.outliers.iter().map(|outlier| outlier.wine.id).collect();
+        assert_eq!(ids, [9]);
+        assert!(report.outliers[0].z_score < -2.0);
+
+        // White Wine's 88 and 86.5 look like a spread, but two wines are too few to judge.
+        let report: VarietyOutliers = server.get("/varieties/White Wine/outliers").await.json();
+        assert_eq!(report.count, 2);
+        assert!(report.outliers.is_empty());
+        let report: VarietyOutliers = server.get("/varieties/Rosé/outliers").await.json();
+        assert_eq!((report.count, report.std_dev), (1, None));
+
+        server.get("/varieties/Sparkling/outliers").await.assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_get_rating_outliers() {
+        let pool = setup_test_db().await;
+        sqlx::query("INSERT INTO wine_ratings (id, name, variety, rating) VALUES (6, 'Corked Merlot', 'Red Wine', 20.0)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines/outliers").await;
+        response.assert_status_ok();
+        let outliers: Vec<RatingOutlier> = response.json();
+        assert_eq!(outliers.len(), 1);
+        assert_eq!(outliers[0].wine.id, 6);
+        assert!(outliers[0].z_score < -2.0);
+
+        let response = server.get("/wines/outliers").add_query_param("sigma", "3").await;
+        let outliers: Vec<RatingOutlier> = response.json();
+        assert!(outliers.is_empty());
+
+        let response = server.get("/wines/outliers").add_query_param("sigma", "0").await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_integrity_detects_orphaned_reviews() {
+        let pool = setup_test_db().await;
+        insert_review(&pool, 1, 93.0, "Fine").await;
+        let server = admin_test_server(pool.clone());
+
+        let response = server.get("/admin/integrity").await;
+        response.assert_status_ok();
+        let report: IntegrityReport = response.json();
+        assert_eq!(report.orphaned_reviews, 0);
+        assert!(report.healthy);
+
+        let mut conn = pool.acquire().await.unwrap();
+        sqlx::query("PRAGMA foreign_keys = OFF").execute(&mut *conn).await.unwrap();
+        sqlx::query("INSERT INTO reviews (wine_id, score, comment) VALUES (42, 80.0, 'Wine was deleted')")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        sqlx::query("PRAGMA foreign_keys = ON").execute(&mut *conn).await.unwrap();
+        drop(conn);
+
+        let response = server.get("/admin/integrity").await;
+        let report: IntegrityReport = response.json();
+        assert_eq!(report.orphaned_reviews, 1);
+        assert!(!report.healthy);
+    }
+
+    #[tokio::test]
+    async fn test_get_wines_as_ndjson() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines").add_query_param("format", "ndjson").await;
+        response.assert_status_ok();
+        assert_eq!(response.header("content-type"), "application/x-ndjson");
+
+        let text = response.text();
+        let wines: Vec<Wine> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(wines.len(), 5);
+        assert_eq!(wines[3].name, "Bourbon Barrel Aged Red");
+        assert!(text.ends_with('\n'));
+    }
+
+    #[tokio::test]
+    async fn test_filter_wines_by_exact_variety() {
+        let pool = setup_test_db().await;
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, region, variety, rating) VALUES
+            (6, 'Left Bank 2018', 'Bordeaux, France', 'Bordeaux Red Wine Blend', 93.0)"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines").add_query_param("variety", "Red Wine").await;
+        let wines: Vec<Wine> = response.json();
+        assert_eq!(wines.len(), 4);
+
+        let response = server.get("/wines").add_query_param("variety_exact", "Red Wine").await;
+        response.assert_status_ok();
+        let wines: Vec<Wine> = response.json();
+        assert_eq!(wines.len(), 3);
+        assert!(wines.iter().all(|w| w.variety.as_deref() == Some("Red Wine")));
+
+        let response = server.get("/wines").add_query_param("region_exact", "Bordeaux").await;
+        let wines: Vec<Wine> = response.json();
+        assert!(wines.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_request_counter() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        server.get("/wines").await.assert_status_ok();
+        server.get("/regions").await.assert_status_ok();
+        server.get("/wines/99").await.assert_status_not_found();
+
+        let response = server.get("/stats/requests").await;
+        response.assert_status_ok();
+        let stats: RequestStats = response.json();
+        assert_eq!(stats.total_requests, 4);
+    }
+
+    #[tokio::test]
+    async fn test_get_pool_stats() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/stats/pool").await;
+        response.assert_status_ok();
+        let stats: PoolStats = response.json();
+        assert!(stats.size >= 1);
+        assert!(stats.size <= stats.max_connections);
+        assert_eq!(stats.idle + stats.in_use, stats.size as usize);
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_gauge_tracks_running_requests() {
+        let in_flight = InFlightGauge::default();
+        let release = Arc::new(tokio::sync::Notify::new());
+        let app = Router::new()
+            .route(
+                "/slow",
+                get({
+                    let release = release.clone();
+                    move || async move {
+                        release.notified().await;
+                        StatusCode::OK
+                    }
+                }),
+            )
+            .layer(middleware::from_fn_with_state(in_flight.clone(), track_in_flight));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        assert_eq!(in_flight.get(), 0);
+        let request = tokio::spawn(reqwest::get(format!("http://{}/slow", addr)));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while in_flight.get() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(in_flight.get(), 1);
+
+        release.notify_one();
+        let response = request.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(in_flight.get(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_request_counter_under_concurrency() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = reqwest::Client::new();
+        let mut requests = tokio::task::JoinSet::new();
+        for _ in 0..50 {
+            let client = client.clone();
+            let url = format!("http://{}/stats/requests", addr);
+            requests.spawn(async move { client.get(url).send().await.unwrap().status() });
+        }
+        while let Some(status) = requests.join_next().await {
+            assert_eq!(status.unwrap(), StatusCode::OK);
+        }
+
+        let stats: RequestStats = client
+            .get(format!("http://{}/stats/requests", addr))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(stats.total_requests, 51);
+    }
+
+    #[tokio::test]
+    async fn test_get_wines_starting_with() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines/starts-with/t").await;
+        response.assert_status_ok();
+        let wines: Vec<Wine> = response.json();
+        let names: Vec<&str> = wines.iter().map(|w| w.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "Test Cabernet 2020",
+                "Test Chardonnay 2021",
+                "Test Pinot Noir 2019",
+                "Test Sauvignon Blanc",
+            ]
+        );
+
+        let response = server.get("/wines/starts-with/B").await;
+        let wines: Vec<Wine> = response.json();
+        assert_eq!(wines.len(), 1);
+
+        server.get("/wines/starts-with/te").await.assert_status(StatusCode::BAD_REQUEST);
+        server.get("/wines/starts-with/1").await.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_keys_are_sorted() {
+        let pool = setup_test_db().await;
+        sqlx::query("INSERT INTO wine_ratings (id, name, region, variety, rating) VALUES (6, 'Alsace Riesling', 'Alsace', 'Riesling', 89.0)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        fn key_order(body: &str, keys: &[&str]) -> Vec<usize> {
+            keys.iter().map(|key| body.find(&format!("\"{}\":", key)).unwrap()).collect()
+        }
+        fn is_sorted(positions: &[usize]) -> bool {
+            positions.windows(2).all(|pair| pair[0] < pair[1])
+        }
+
+        let body = server.get("/regions").await.text();
+        assert!(is_sorted(&key_order(&body, &["Alsace", "California", "Oregon", "Texas", "Washington"])), "{}", body);
+
+        let body = server.get("/varieties").await.text();
+        assert!(is_sorted(&key_order(&body, &["Red Wine", "Riesling", "White Wine"])), "{}", body);
+
+        let body = server.post("/wines/by-regions").json(&serde_json::json!(["Texas", "Alsace"])).await.text();
+        assert!(is_sorted(&key_order(&body, &["Alsace", "Texas"])), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_get_regions() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/regions").await;
+        response.assert_status_ok();
+        
+        let regions: HashMap<String, i64> = response.json();
+        assert_eq!(regions.len(), 4);
+        assert_eq!(regions.get("California"), Some(&2));
+        assert_eq!(regions.get("Oregon"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_get_varieties() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/varieties").await;
+        response.assert_status_ok();
+        
+        let varieties: HashMap<String, VarietyInfo> = response.json();
+        assert_eq!(varieties.len(), 2);
+        
+        let red_wine = varieties.get("Red Wine").unwrap();
+        assert_eq!(red_wine.count, 3);
+        assert!((red_wine.avg_rating - 92.5).abs() < 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_get_variety_leaders() {
+        let pool = setup_test_db().await;
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, variety, rating) VALUES
+            (6, 'Tied White', 'White Wine', 88.0),
+            (7, 'Unrated Red', 'Red Wine', NULL)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/varieties/leaders").await;
+        response.assert_status_ok();
+        let leaders: HashMap<String, VarietyLeader> = response.json();
+        assert_eq!(leaders.len(), 2);
+        assert_eq!(leaders["Red Wine"].name, "Bourbon Barrel Aged Red");
+        assert_eq!(leaders["Red Wine"].rating, 95.0);
+        assert_eq!(leaders["White Wine"].id, 2);
+    }
+
+    #[tokio::test]
+    async fn test_highest_rated_compares_normalized_ratings() {
+        let pool = setup_test_db().await;
+        set_source_scales(&pool, &[("Decanter".to_string(), 20.0)]).await.unwrap();
+        // 19.5/20 and 19/20 rescale to 97.5 and 95, above the 95 and 92.5 they would lose to raw.
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, variety, rating, source) VALUES
+            (6, 'Scaled Syrah', 'Red Wine', 19.5, 'Decanter'),
+            (7, 'Test Cabernet 2020', 'Red Wine', 19.0, 'Decanter')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let server = TestServer::new(create_app(pool)).unwrap();
+
+        let leaders: HashMap<String, VarietyLeader> = server.get("/varieties/leaders").await.json();
+        assert_eq!(leaders["Red Wine"].id, 6);
+
+        let response = server.get("/wines").add_query_param("distinct_names", "true").await;
+        let ids: Vec<i64> = response.json::<Vec<Wine>>().iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![2, 3, 4, 5, 6, 7]);
+    }
+
+    #[tokio::test]
+    async fn test_wine_drinkability() {
+        let pool = setup_test_db().await;
+        let year = current_year();
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, variety, rating, vintage) VALUES
+            (6, 'Fresh White', 'White Wine', 90.0, ?),
+            (7, 'Tired White', 'White Wine', 90.0, ?)",
+        )
+        .bind(year - 2)
+        .bind(year - 15)
+        .execute(&pool)
+        .await
+        .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let fresh: Drinkability = server.get("/wines/6/drinkability").await.json();
+        let tired: Drinkability = server.get("/wines/7/drinkability").await.json();
+        assert_eq!((fresh.age, fresh.peak_age), (2, 3.0));
+        assert_eq!(tired.age, 15);
+        assert!(tired.score < fresh.score, "{} >= {}", tired.score, fresh.score);
+        assert!(fresh.score <= fresh.rating);
+
+        let response = server.get("/wines/1/drinkability").await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_get_region_trend() {
+        let pool = setup_test_db().await;
+        sqlx::query("UPDATE wine_ratings SET vintage = 2020 WHERE id = 1").execute(&pool).await.unwrap();
+        sqlx::query("UPDATE wine_ratings SET vintage = 2021 WHERE id = 2").execute(&pool).await.unwrap();
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, region, rating, vintage) VALUES
+            (6, 'Second 2020', 'California', 89.5, 2020),
+            (7, 'Unrated 2021', 'California', NULL, 2021),
+            (8, 'Undated', 'California', 70.0, NULL)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/regions/California/trend").await;
+        response.assert_status_ok();
+        let trend: Vec<VintageTrend> = response.json();
+        let points: Vec<(i64, f64, i64)> = trend.iter().map(|t| (t.vintage, t.avg_rating, t.count)).collect();
+        assert_eq!(points, vec![(2020, 91.0, 2), (2021, 88.0, 1)]);
+
+        let response = server.get("/regions/Oregon/trend").await;
+        response.assert_status_ok();
+        assert!(response.json::<Vec<VintageTrend>>().is_empty());
+
+        let response = server.get("/regions/Atlantis/trend").await;
+        response.assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_varieties_round_average_rating() {
+        let pool = setup_test_db().await;
+        sqlx::query("INSERT INTO wine_ratings (id, name, variety, rating) VALUES (6, 'Third White', 'White Wine', 87.0)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let server = TestServer::new(create_app(pool.clone())).unwrap();
+
+        let varieties: HashMap<String, VarietyInfo> = server.get("/varieties").await.json();
+        assert_eq!(varieties["White Wine"].avg_rating, 87.17);
+
+        let config = Config {
+            rating_decimals: 0,
+            ..Config::default()
+        };
+        let server = TestServer::new(create_app_with_config(pool, &config)).unwrap();
+        let varieties: HashMap<String, VarietyInfo> = server.get("/varieties").await.json();
+        assert_eq!(varieties["White Wine"].avg_rating, 87.0);
+    }
+
+    #[tokio::test]
+    async fn test_search_wines() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines/search").add_query_param("q", "bourbon").await;
+        response.assert_status_ok();
+        
+        let wines: Vec<Wine> = response.json();
+        assert_eq!(wines.len(), 1);
+        assert!(wines[0].name.contains("Bourbon") || wines[0].notes.as_ref().unwrap().contains("bourbon"));
+    }
+
+    #[tokio::test]
+    async fn test_search_wines_caps_broad_searches() {
+        let pool = setup_test_db().await;
+        let config = Config {
+            max_search_results: 3,
+            ..Config::default()
+        };
+        let app = create_app_with_config(pool, &config);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines/search").add_query_param("q", "e").await;
+        response.assert_status_ok();
+        assert_eq!(response.header("x-total-count"), "5");
+        assert_eq!(response.header("x-search-truncated"), "true");
+        assert_eq!(response.json::<Vec<Wine>>().len(), 3);
+
+        let response = server
+            .get("/wines/search")
+            .add_query_param("q", "e")
+            .add_query_param("offset", "2")
+            .await;
+        assert_eq!(response.json::<Vec<Wine>>().len(), 1);
+
+        // Paging past the cap finds nothing; the header still counts all five matches.
+        let response = server
+            .get("/wines/search")
+            .add_query_param("q", "e")
+            .add_query_param("offset", "3")
+            .await;
+        assert_eq!(response.header("x-total-count"), "5");
+        assert_eq!(response.header("x-search-truncated"), "true");
+        assert!(response.json::<Vec<Wine>>().is_empty());
+
+        let response = server.get("/wines/search").add_query_param("q", "bourbon").await;
+        assert_eq!(response.json::<Vec<Wine>>().len(), 1);
+        assert_eq!(response.maybe_header("x-search-truncated"), None);
+    }
+
+    #[tokio::test]
+    async fn test_search_wines_suggests_close_name() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines/search").add_query_param("q", "cabernay").await;
+        response.assert_status_ok();
+        assert!(response.json::<Vec<Wine>>().is_empty());
+        assert_eq!(response.header("x-search-suggestion"), "Test Cabernet 2020");
+
+        let response = server.get("/wines/search").add_query_param("q", "xylophone").await;
+        assert!(response.json::<Vec<Wine>>().is_empty());
+        assert_eq!(response.maybe_header("x-search-suggestion"), None);
+
+        let response = server.get("/wines/search").add_query_param("q", "bourbon").await;
+        assert_eq!(response.maybe_header("x-search-suggestion"), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_wines_by_flavor() {
+        let pool = setup_test_db().await;
+        sqlx::query("INSERT INTO wine_ratings (id, name, notes) VALUES (6, 'Dessert Red', 'Black cherry and Vanilla cream')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.post("/wines/by-flavor").json(&["cherry", "vanilla"]).await;
+        response.assert_status_ok();
+        let matches: Vec<FlavorMatch> = response.json();
+        let ranked: Vec<(i64, usize)> = matches.iter().map(|m| (m.wine.id, m.matched_flavors)).collect();
+        assert_eq!(ranked, vec![(6, 2), (1, 1), (4, 1)]);
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("cabernet", "cabernet"), 0);
+        assert_eq!(edit_distance("cabernay", "cabernet"), 2);
+        assert_eq!(edit_distance("", "pinot"), 5);
+        assert_eq!(edit_distance("rosé", "rose"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_search_wines_ignores_accents() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool.clone());
+        let server = TestServer::new(app).unwrap();
+        server
+            .post("/wines")
+            .json(&serde_json::json!({"name": "Provence Rosé", "notes": "Pâle et élégant"}))
+            .await
+            .assert_status(StatusCode::CREATED);
+
+        let response = server.get("/wines/search").add_query_param("q", "rose").await;
+        response.assert_status_ok();
+        let wines = response.json::<Vec<Wine>>();
+        assert_eq!(wines.len(), 1);
+        assert_eq!(wines[0].name, "Provence Rosé");
+        assert_eq!(wines[0].notes.as_deref(), Some("Pâle et élégant"));
+
+        let response = server.get("/wines/search").add_query_param("q", "ELÉGANT").await;
+        let wines = response.json::<Vec<Wine>>();
+        assert_eq!(wines.len(), 1);
+
+        // Written behind the API's back: only plain lowercasing applies until the backfill runs.
+        sqlx::query("INSERT INTO wine_ratings (name) VALUES ('Crémant de Loire')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let search = |term: &'static str| server.get("/wines/search").add_query_param("q", term);
+        assert_eq!(search("LOIRE").await.json::<Vec<Wine>>().len(), 1);
+        assert!(search("cremant").await.json::<Vec<Wine>>().is_empty());
+        backfill_search_text(&pool).await.unwrap();
+        assert_eq!(search("cremant").await.json::<Vec<Wine>>().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_search_wines_with_highlight() {
+        let pool = setup_test_db().await;
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, notes) VALUES
+            (6, 'Cherry <Bomb>', 'CHERRY cola & black cherry')"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .get("/wines/search")
+            .add_query_param("q", "cherry")
+            .add_query_param("highlight", "true")
+            .await;
+        response.assert_status_ok();
+
+        let wines = response.json::<Vec<HighlightedWine>>();
+        assert_eq!(wines.len(), 2);
+        assert_eq!(wines[0].highlighted_name, "<mark>Cherry</mark> &lt;Bomb&gt;");
+        assert_eq!(
+            wines[0].highlighted_notes.as_deref(),
+            Some("<mark>CHERRY</mark> cola &amp; black <mark>cherry</mark>")
+        );
+        assert_eq!(wines[0].wine.name, "Cherry <Bomb>");
+        assert_eq!(
+            wines[1].highlighted_notes.as_deref(),
+            Some("Rich and bold with notes of <mark>cherry</mark>")
+        );
+        assert_eq!(wines[1].highlighted_name, "Test Cabernet 2020");
+    }
+
+    #[test]
+    fn test_highlight_matches_accented_text() {
+        assert_eq!(highlight_matches("Provence Rosé", "rose"), "Provence <mark>Rosé</mark>");
+    }
+
+    #[tokio::test]
+    async fn test_search_wines_pagination() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let mut seen = Vec::new();
+        for offset in [0, 2, 4] {
+            let response = server
+                .get("/wines/search")
+                .add_query_param("q", "e")
+                .add_query_param("limit", "2")
+                .add_query_param("offset", offset)
+                .await;
+            response.assert_status_ok();
+            assert_eq!(response.header("x-total-count"), "5");
+            let wines = response.json::<Vec<Wine>>();
+            assert!(wines.len() <= 2);
+            seen.extend(wines.into_iter().map(|w| w.id));
+        }
+
+        let mut unique = seen.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(seen.len(), 5);
+        assert_eq!(unique.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_search_wines_ranks_name_matches_first() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines/search").add_query_param("q", "bo").await;
+        let wines = response.json::<Vec<Wine>>();
+        let ids: Vec<i64> = wines.iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![4, 1, 3]);
     }
-}
 
-async fn get_regions(
-    axum::extract::State(pool): axum::extract::State<SqlitePool>,
-) -> Result<Json<HashMap<String, i64>>, StatusCode> {
-    let rows = sqlx::query("SELECT region, COUNT(*) as count FROM wine_ratings WHERE region IS NOT NULL GROUP BY region")
-        .fetch_all(&pool)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    let mut regions = HashMap::new();
-    for row in rows {
-        let region: String = row.get("region");
-        let count: i64 = row.get("count");
-        regions.insert(region, count);
+    #[tokio::test]
+    async fn test_max_page_size_clamps_by_default() {
+        let pool = setup_test_db().await;
+        let config = Config {
+            max_page_size: Some(2),
+            ..Config::default()
+        };
+        let server = TestServer::new(create_app_with_config(pool, &config)).unwrap();
+
+        let response = server.get("/wines").add_query_param("limit", "50").await;
+        response.assert_status_ok();
+        let wines: Vec<Wine> = response.json();
+        assert_eq!(wines.len(), 2);
+        assert_eq!(response.header("x-total-count"), "5");
+
+        let wines: Vec<Wine> = server.get("/wines").await.json();
+        assert_eq!(wines.len(), 2);
     }
-    
-    Ok(Json(regions))
-}
 
-async fn get_varieties(
-    axum::extract::State(pool): axum::extract::State<SqlitePool>,
-) -> Result<Json<HashMap<String, VarietyInfo>>, StatusCode> {
-    let rows = sqlx::query("SELECT variety, COUNT(*) as count, AVG(rating) as avg_rating FROM wine_ratings WHERE variety IS NOT NULL AND rating IS NOT NULL GROUP BY variety")
-        .fetch_all(&pool)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    let mut varieties = HashMap::new();
-    for row in rows {
-        let variety: String = row.get("variety");
-        let count: i64 = row.get("count");
-        let avg_rating: f64 = row.get("avg_rating");
-        varieties.insert(variety, VarietyInfo { count, avg_rating });
+    #[tokio::test]
+    async fn test_max_page_size_reject_mode() {
+        let pool = setup_test_db().await;
+        let config = Config {
+            max_page_size: Some(2),
+            page_size_mode: PageSizeMode::Reject,
+            ..Config::default()
+        };
+        let server = TestServer::new(create_app_with_config(pool, &config)).unwrap();
+
+        let response = server.get("/wines").add_query_param("limit", "50").await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+        let error: ErrorMessage = response.json();
+        assert_eq!(error.error, "limit must not exceed the maximum page size of 2");
+
+        let response = server.get("/wines/search").add_query_param("q", "e").add_query_param("limit", "3").await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+
+        let response = server.get("/wines").add_query_param("limit", "2").await;
+        response.assert_status_ok();
     }
-    
-    Ok(Json(varieties))
-}
 
-async fn search_wines(
-    Query(search): Query<SearchQuery>,
-    axum::extract::State(pool): axum::extract::State<SqlitePool>,
-) -> Result<Json<Vec<Wine>>, StatusCode> {
-    let query = "SELECT id, name, region, variety, rating, notes FROM wine_ratings WHERE name LIKE ? OR notes LIKE ?";
-    let search_term = format!("%{}%", search.q);
-    
-    let wines = sqlx::query_as::<_, Wine>(query)
-        .bind(&search_term)
-        .bind(&search_term)
-        .fetch_all(&pool)
+    #[tokio::test]
+    async fn test_keyword_and_min_rating_combine_with_and() {
+        let pool = setup_test_db().await;
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, rating, notes) VALUES
+            (6, 'Reserve Chardonnay', 94.0, 'Buttery with toasted oak'),
+            (7, 'House Red', 85.0, 'Simple, a touch of oak'),
+            (8, 'Grand Cru', 97.0, 'Mineral and taut')",
+        )
+        .execute(&pool)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    Ok(Json(wines))
-}
+        .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
 
-async fn get_wines_by_region(
-    Path(region): Path<String>,
-    axum::extract::State(pool): axum::extract::State<SqlitePool>,
-) -> Result<Json<Vec<Wine>>, StatusCode> {
-    let wines = sqlx::query_as::<_, Wine>("SELECT id, name, region, variety, rating, notes FROM wine_ratings WHERE region = ?")
-        .bind(region)
-        .fetch_all(&pool)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    Ok(Json(wines))
-}
+        let response = server
+            .get("/wines")
+            .add_query_param("q", "oak")
+            .add_query_param("min_rating", "93")
+            .await;
+        response.assert_status_ok();
 
-pub fn create_app(pool: SqlitePool) -> Router {
-    Router::new()
-        .route("/wines/search", get(search_wines))
-        .route("/wines/region/:region", get(get_wines_by_region))
-        .route("/wines", get(get_wines))
-        .route("/regions", get(get_regions))
-        .route("/varieties", get(get_varieties))
-        .layer(CorsLayer::permissive())
-        .with_state(pool)
-}
+        let wines: Vec<Wine> = response.json();
+        let ids: Vec<i64> = wines.iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![6]);
+    }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let database_url = std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:wine_ratings.db".to_string());
-    let pool = SqlitePool::connect(&database_url).await?;
-    let app = create_app(pool);
-    
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
-    println!("Wine API server running on http://0.0.0.0:3000");
-    
-    axum::serve(listener, app).await?;
-    
-    Ok(())
-}
+    #[tokio::test]
+    async fn test_write_endpoints_require_json_content_type() {
+        let pool = setup_test_db().await;
+        let server = admin_test_server(pool);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use axum_test::TestServer;
-    use sqlx::SqlitePool;
+        let body = r#"{"name": "Plain Text Red"}"#;
+        let response = server
+            .post("/wines")
+            .text(body)
+            .content_type("text/plain")
+            .await;
+        response.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert!(response.json::<ErrorMessage>().error.contains("text/plain"));
 
-    async fn setup_test_db() -> SqlitePool {
-        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
-        
+        let response = server
+            .put("/wines/by-external/ext-1")
+            .text(body)
+            .content_type("text/plain")
+            .await;
+        response.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let response = server
+            .post("/wines")
+            .text(body)
+            .content_type("application/json; charset=utf-8")
+            .await;
+        response.assert_status(StatusCode::CREATED);
+
+        let response = server.post("/admin/optimize").await;
+        response.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_has_notes() {
+        let pool = setup_test_db().await;
         sqlx::query(
-            "CREATE TABLE wine_ratings (
-                id INTEGER PRIMARY KEY,
-                name TEXT NOT NULL,
-                region TEXT,
-                variety TEXT,
-                rating REAL,
-                notes TEXT
-            )"
+            "INSERT INTO wine_ratings (id, name, notes) VALUES
+            (6, 'Blank Notes', ''),
+            (7, 'No Notes', NULL)",
         )
         .execute(&pool)
         .await
         .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines").add_query_param("has_notes", "false").await;
+        response.assert_status_ok();
+        let ids: Vec<i64> = response.json::<Vec<Wine>>().iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![6, 7]);
+
+        let response = server.get("/wines").add_query_param("has_notes", "true").await;
+        let ids: Vec<i64> = response.json::<Vec<Wine>>().iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+    }
 
+    #[tokio::test]
+    async fn test_popular_searches() {
+        let pool = setup_test_db().await;
+        sqlx::query("INSERT INTO search_log (term, searched_at) VALUES ('merlot', datetime('now', '-30 days'))")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        for term in ["Cherry", " cherry  ", "CHERRY", "oak"] {
+            server.get("/wines/search").add_query_param("q", term).await.assert_status_ok();
+        }
+
+        let response = server.get("/stats/popular-searches").add_query_param("window", "7d").await;
+        response.assert_status_ok();
+        let popular: Vec<PopularSearch> = response.json();
+        let counts: Vec<(&str, i64)> = popular.iter().map(|p| (p.term.as_str(), p.count)).collect();
+        assert_eq!(counts, vec![("cherry", 3), ("oak", 1)]);
+
+        let response = server.get("/stats/popular-searches").add_query_param("window", "60d").await;
+        assert_eq!(response.json::<Vec<PopularSearch>>().len(), 3);
+
+        for window in ["7w", "0d", "7é"] {
+            let response = server.get("/stats/popular-searches").add_query_param("window", window).await;
+            response.assert_status(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_distinct_names() {
+        let pool = setup_test_db().await;
         sqlx::query(
-            "INSERT INTO wine_ratings (id, name, region, variety, rating, notes) VALUES 
-            (1, 'Test Cabernet 2020', 'California', 'Red Wine', 92.5, 'Rich and bold with notes of cherry'),
-            (2, 'Test Chardonnay 2021', 'California', 'White Wine', 88.0, 'Crisp and clean with citrus notes'),
-            (3, 'Test Pinot Noir 2019', 'Oregon', 'Red Wine', 90.0, 'Light bodied with earthy undertones'),
-            (4, 'Bourbon Barrel Aged Red', 'Texas', 'Red Wine', 95.0, 'Aged in bourbon barrels with vanilla notes'),
-            (5, 'Test Sauvignon Blanc', 'Washington', 'White Wine', 86.5, 'Fresh and herbaceous')"
+            "INSERT INTO wine_ratings (id, name, region, rating, vintage) VALUES
+            (6, 'Test Cabernet 2020', 'California', 94.0, 2021),
+            (7, 'test cabernet 2020 ', 'California', 89.0, 2022)",
         )
         .execute(&pool)
         .await
         .unwrap();
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
 
-        pool
+        let response = server.get("/wines").add_query_param("distinct_names", "true").await;
+        response.assert_status_ok();
+        assert_eq!(response.header("x-total-count"), "5");
+        let ids: Vec<i64> = response.json::<Vec<Wine>>().iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![2, 3, 4, 5, 6]);
+
+        let response = server
+            .get("/wines")
+            .add_query_param("distinct_names", "true")
+            .add_query_param("max_rating", "93")
+            .await;
+        let ids: Vec<i64> = response.json::<Vec<Wine>>().iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 5]);
     }
 
     #[tokio::test]
-    async fn test_get_all_wines() {
+    async fn test_get_wine_facets() {
         let pool = setup_test_db().await;
         let app = create_app(pool);
         let server = TestServer::new(app).unwrap();
 
-        let response = server.get("/wines").await;
+        let response = server.get("/wines/facets").await;
         response.assert_status_ok();
-        
-        let wines: Vec<Wine> = response.json();
-        assert_eq!(wines.len(), 5);
+        let facets: WineFacets = response.json();
+        assert_eq!(facets.region.values().sum::<i64>(), 5);
+        assert_eq!(facets.variety.get("Red Wine"), Some(&3));
+
+        let facets: WineFacets = server
+            .get("/wines/facets")
+            .add_query_param("min_rating", "90")
+            .add_query_param("region_exact", "California")
+            .await
+            .json();
+        assert_eq!(
+            facets.region,
+            BTreeMap::from([("California".to_string(), 1), ("Oregon".to_string(), 1), ("Texas".to_string(), 1)])
+        );
+        assert_eq!(facets.variety, BTreeMap::from([("Red Wine".to_string(), 1)]));
     }
 
     #[tokio::test]
-    async fn test_filter_wines_by_region() {
+    async fn test_display_currency_converts_prices() {
+        let pool = setup_test_db().await;
+        let vars = HashMap::from([("CURRENCY_RATES".to_string(), "EUR=0.9, GBP=0.8".to_string())]);
+        let config = Config::from_vars(&vars).unwrap();
+        let server = TestServer::new(create_app_with_config(pool, &config)).unwrap();
+
+        let mut ids = Vec::new();
+        for (name, currency) in [("Dollar Malbec", "USD"), ("Sterling Claret", "GBP"), ("Yen Koshu", "JPY")] {
+            let response = server
+                .post("/wines")
+                .json(&serde_json::json!({"name": name, "price": 20.0, "currency": currency}))
+                .await;
+            response.assert_status(StatusCode::CREATED);
+            ids.push(response.json::<Wine>().id);
+        }
+
+        let response = server.get(&format!("/wines/{}", ids[0])).add_query_param("display_currency", "EUR").await;
+        let wine: Wine = response.json();
+        assert_eq!(wine.price, Some(20.0));
+        assert_eq!(wine.price_converted, Some(18.0));
+
+        let response = server
+            .get("/wines")
+            .add_query_param("q", "e")
+            .add_query_param("display_currency", "eur")
+            .await;
+        let converted: HashMap<i64, Option<f64>> =
+            response.json::<Vec<Wine>>().into_iter().map(|wine| (wine.id, wine.price_converted)).collect();
+        assert_eq!(converted[&ids[1]], Some(22.5));
+        // No JPY rate, so there is nothing to convert with.
+        assert_eq!(converted[&ids[2]], None);
+
+        let response = server.get(&format!("/wines/{}", ids[0])).await;
+        assert!(response.json::<serde_json::Value>().get("price_converted").is_none());
+
+        let response = server.post("/wines").json(&serde_json::json!({"name": "Vague Vin", "currency": "euro"})).await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        let vars = HashMap::from([("CURRENCY_RATES".to_string(), "eur=0.9".to_string())]);
+        assert_eq!(Config::from_vars(&vars).unwrap_err().key, "CURRENCY_RATES");
+    }
+
+    #[tokio::test]
+    async fn test_min_reviews_filter() {
+        let pool = setup_test_db().await;
+        for wine_id in [1, 1, 3] {
+            sqlx::query("INSERT INTO reviews (wine_id, score) VALUES (?, 90)")
+                .bind(wine_id)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let server = TestServer::new(create_app(pool)).unwrap();
+        let ids = |response: axum_test::TestResponse| -> Vec<i64> {
+            response.json::<Vec<Wine>>().iter().map(|wine| wine.id).collect()
+        };
+
+        assert_eq!(ids(server.get("/wines").add_query_param("min_reviews", "2").await), vec![1]);
+        assert_eq!(ids(server.get("/wines").add_query_param("min_reviews", "1").await), vec![1, 3]);
+        let response = server
+            .get("/wines")
+            .add_query_param("min_reviews", "1")
+            .add_query_param("region", "Oregon")
+            .await;
+        assert_eq!(ids(response), vec![3]);
+        assert_eq!(ids(server.get("/wines").add_query_param("min_reviews", "0").await).len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_notes_length_truncates_list_notes() {
         let pool = setup_test_db().await;
+        sqlx::query("UPDATE wine_ratings SET notes = 'Crème brûlée, pêche and a long finish' WHERE id = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
         let app = create_app(pool);
         let server = TestServer::new(app).unwrap();
 
-        let response = server.get("/wines").add_query_param("region", "California").await;
+        let response = server.get("/wines").add_query_param("notes_length", "12").await;
         response.assert_status_ok();
-        
         let wines: Vec<Wine> = response.json();
-        assert_eq!(wines.len(), 2);
-        assert!(wines.iter().all(|w| w.region.as_ref().unwrap().contains("California")));
+        assert_eq!(wines[0].notes.as_deref(), Some("Crème brûlé…"));
+        assert_eq!(wines[0].notes.as_ref().unwrap().chars().count(), 12);
+        assert_eq!(wines[4].notes.as_deref(), Some("Fresh and h…"));
+
+        let response = server.get("/wines").add_query_param("notes_length", "1000").await;
+        assert_eq!(response.json::<Vec<Wine>>()[4].notes.as_deref(), Some("Fresh and herbaceous"));
+
+        let wine: Wine = server.get("/wines/1").await.json();
+        assert_eq!(wine.notes.as_deref(), Some("Crème brûlée, pêche and a long finish"));
+
+        server.get("/wines").add_query_param("notes_length", "0").await.assert_status_bad_request();
     }
 
     #[tokio::test]
-    async fn test_filter_wines_by_rating() {
+    async fn test_filter_above_average() {
         let pool = setup_test_db().await;
+        sqlx::query("INSERT INTO wine_ratings (id, name) VALUES (6, 'Unrated Blend')")
+            .execute(&pool)
+            .await
+            .unwrap();
         let app = create_app(pool);
         let server = TestServer::new(app).unwrap();
 
-        let response = server.get("/wines").add_query_param("min_rating", "90").await;
+        // Seeded mean: (92.5 + 88.0 + 90.0 + 95.0 + 86.5) / 5 = 90.4
+        let response = server.get("/wines").add_query_param("above_average", "true").await;
+        response.assert_status_ok();
+        let ids: Vec<i64> = response.json::<Vec<Wine>>().iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![1, 4]);
+
+        let response = server.get("/wines").add_query_param("above_average", "false").await;
+        let ids: Vec<i64> = response.json::<Vec<Wine>>().iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![2, 3, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_filter_above_average_normalizes_scales() {
+        let pool = setup_test_db().await;
+        set_source_scales(&pool, &[("Decanter".to_string(), 20.0)]).await.unwrap();
+        sqlx::query("INSERT INTO wine_ratings (id, name, rating, source) VALUES (6, 'Scaled Syrah', 19.0, 'Decanter')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let server = TestServer::new(create_app(pool)).unwrap();
+
+        // 19/20 counts as 95, lifting the mean to (90.4 * 5 + 95) / 6 ≈ 91.2.
+        let response = server.get("/wines").add_query_param("above_average", "true").await;
+        let ids: Vec<i64> = response.json::<Vec<Wine>>().iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![1, 4, 6]);
+
+        let response = server.get("/wines").add_query_param("above_average", "false").await;
+        let ids: Vec<i64> = response.json::<Vec<Wine>>().iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![2, 3, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_grade() {
+        let pool = setup_test_db().await;
+        let app = create_app(pool);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/wines").add_query_param("grade", "A").await;
         response.assert_status_ok();
-        
         let wines: Vec<Wine> = response.json();
-        assert_eq!(wines.len(), 3);
         assert!(wines.iter().all(|w| w.rating.unwrap() >= 90.0));
+        assert_eq!(wines.iter().map(|w| w.id).collect::<Vec<_>>(), vec![1, 3, 4]);
+
+        let response = server.get("/wines").add_query_param("grade", "B").await;
+        let ids: Vec<i64> = response.json::<Vec<Wine>>().iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![2, 5]);
+
+        server.get("/wines").add_query_param("grade", "E").await.assert_status_bad_request();
     }
 
     #[tokio::test]
-    async fn test_get_regions() {
+    async fn test_run_query_from_cli_args() {
+        let pool = setup_test_db().await;
+        let args: Vec<String> = ["--region", "California", "--min-rating=90"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let (tenant, filters) = parse_query_args(&args).unwrap();
+
+        let output = run_query(&pool, &tenant, &filters, &Config::default()).await.unwrap();
+        let wines: Vec<Wine> = serde_json::from_str(&output).unwrap();
+        let ids: Vec<i64> = wines.iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![1]);
+    }
+
+    #[test]
+    fn test_parse_query_args_rejects_bad_input() {
+        let parse = |args: &[&str]| parse_query_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>());
+        assert!(parse(&["--colour", "red"]).is_err());
+        assert!(parse(&["--min-rating", "high"]).is_err());
+        assert!(parse(&["--region"]).is_err());
+        assert!(parse(&["California"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_saved_filter_permalink() {
         let pool = setup_test_db().await;
         let app = create_app(pool);
         let server = TestServer::new(app).unwrap();
 
-        let response = server.get("/regions").await;
+        let expected: Vec<Wine> = server
+            .get("/wines")
+            .add_query_param("variety", "Red Wine")
+            .add_query_param("min_rating", "91")
+            .add_query_param("sort", "-rating")
+            .await
+            .json();
+
+        let response = server
+            .post("/filters")
+            .json(&serde_json::json!({"variety": "Red Wine", "min_rating": 91.0, "sort": "-rating"}))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        let saved: SavedFilter = response.json();
+        assert_eq!(saved.id.len(), SAVED_FILTER_ID_LENGTH);
+
+        let response = server.get(&format!("/filters/{}/wines", saved.id)).await;
         response.assert_status_ok();
-        
-        let regions: HashMap<String, i64> = response.json();
-        assert_eq!(regions.len(), 4);
-        assert_eq!(regions.get("California"), Some(&2));
-        assert_eq!(regions.get("Oregon"), Some(&1));
+        let wines: Vec<Wine> = response.json();
+        let ids: Vec<i64> = wines.iter().map(|w| w.id).collect();
+        assert_eq!(ids, expected.iter().map(|w| w.id).collect::<Vec<_>>());
+        assert_eq!(ids, vec![4, 1]);
+
+        server.get("/filters/missing/wines").await.assert_status_not_found();
+        server
+            .post("/filters")
+            .json(&serde_json::json!({"sort": "price"}))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
     }
 
     #[tokio::test]
-    async fn test_get_varieties() {
+    async fn test_filter_multiple_varieties_within_region() {
         let pool = setup_test_db().await;
+        sqlx::query(
+            "INSERT INTO wine_ratings (id, name, region, variety) VALUES
+            (6, 'Sonoma Pinot', 'California', 'Pinot Noir'),
+            (7, 'Willamette Pinot', 'Oregon', 'Pinot Noir'),
+            (8, 'Napa Rose', 'California', 'Rose')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
         let app = create_app(pool);
         let server = TestServer::new(app).unwrap();
 
-        let response = server.get("/varieties").await;
+        let response = server
+            .get("/wines")
+            .add_query_param("region", "California")
+            .add_query_param("variety", "Red Wine, Pinot Noir")
+            .await;
         response.assert_status_ok();
-        
-        let varieties: HashMap<String, VarietyInfo> = response.json();
-        assert_eq!(varieties.len(), 2);
-        
-        let red_wine = varieties.get("Red Wine").unwrap();
-        assert_eq!(red_wine.count, 3);
-        assert!((red_wine.avg_rating - 92.5).abs() < 1.0);
+
+        let wines: Vec<Wine> = response.json();
+        let ids: Vec<i64> = wines.iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![1, 6]);
     }
 
     #[tokio::test]
-    async fn test_search_wines() {
+    async fn test_get_wines_pagination() {
         let pool = setup_test_db().await;
         let app = create_app(pool);
         let server = TestServer::new(app).unwrap();
 
-        let response = server.get("/wines/search").add_query_param("q", "bourbon").await;
+        let response = server
+            .get("/wines")
+            .add_query_param("sort", "-rating")
+            .add_query_param("limit", "2")
+            .add_query_param("offset", "1")
+            .await;
         response.assert_status_ok();
-        
+        assert_eq!(response.header("x-total-count"), "5");
         let wines: Vec<Wine> = response.json();
-        assert_eq!(wines.len(), 1);
-        assert!(wines[0].name.contains("Bourbon") || wines[0].notes.as_ref().unwrap().contains("bourbon"));
+        let ids: Vec<i64> = wines.iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![1, 3]);
+
+        server.get("/wines").add_query_param("limit", "0").await.assert_status(StatusCode::BAD_REQUEST);
+        server.get("/wines").add_query_param("offset", "-1").await.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_get_wines_without_limit_returns_every_row() {
+        let pool = setup_test_db().await;
+        sqlx::query(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 150)
+            INSERT INTO wine_ratings (name) SELECT 'Extra Wine ' || i FROM n",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let server = TestServer::new(create_app(pool)).unwrap();
+
+        let wines: Vec<Wine> = server.get("/wines").await.json();
+        assert_eq!(wines.len(), 155);
+        let ids: Vec<i64> = server.get("/wines").add_query_param("ids_only", "true").await.json();
+        assert_eq!(ids.len(), 155);
+
+        // Search still pages, defaulting to DEFAULT_PAGE_SIZE matches.
+        let response = server.get("/wines/search").add_query_param("q", "extra").await;
+        assert_eq!(response.json::<Vec<Wine>>().len(), DEFAULT_PAGE_SIZE as usize);
+        assert_eq!(response.header("x-total-count"), "150");
     }
 
     #[tokio::test]
@@ -328,4 +9680,33 @@ mod tests {
         let wines: Vec<Wine> = response.json();
         assert_eq!(wines.len(), 0);
     }
+
+    #[tokio::test]
+    async fn test_get_wines_by_region_strict() {
+        let pool = setup_test_db().await;
+        let server = TestServer::new(create_app(pool)).unwrap();
+
+        let response = server.get("/wines/region/NonExistent").add_query_param("strict", "true").await;
+        response.assert_status_not_found();
+        let response = server.get("/wines/region/Oregon").add_query_param("strict", "true").await;
+        response.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_strict_region_lookup_from_config() {
+        let pool = setup_test_db().await;
+        let config = Config {
+            strict_region_lookup: true,
+            ..Config::default()
+        };
+        let server = TestServer::new(create_app_with_config(pool, &config)).unwrap();
+
+        let response = server.get("/wines/region/NonExistent").await;
+        response.assert_status_not_found();
+
+        let response = server.get("/wines/region/NonExistent").add_query_param("strict", "false").await;
+        response.assert_status_ok();
+        let wines: Vec<Wine> = response.json();
+        assert!(wines.is_empty());
+    }
 }